@@ -0,0 +1,80 @@
+use rhiaqey_sdk_rs::channel::Channel;
+
+#[derive(Clone, Debug)]
+pub enum ChannelChange {
+    Added(Channel),
+    Removed(Channel),
+    Resized {
+        channel: Channel,
+        previous_size: usize,
+    },
+}
+
+impl ChannelChange {
+    pub fn channel(&self) -> &Channel {
+        match self {
+            ChannelChange::Added(channel) => channel,
+            ChannelChange::Removed(channel) => channel,
+            ChannelChange::Resized { channel, .. } => channel,
+        }
+    }
+}
+
+/// Changes needed to go from `previous` to `next`, matching channels by name
+pub fn diff_channels(previous: &[Channel], next: &[Channel]) -> Vec<ChannelChange> {
+    let mut changes = vec![];
+
+    for channel in previous.iter() {
+        if !next.iter().any(|x| x.name == channel.name) {
+            changes.push(ChannelChange::Removed(channel.clone()));
+        }
+    }
+
+    for channel in next.iter() {
+        match previous.iter().find(|x| x.name == channel.name) {
+            None => changes.push(ChannelChange::Added(channel.clone())),
+            Some(existing) if existing.size != channel.size => {
+                changes.push(ChannelChange::Resized {
+                    channel: channel.clone(),
+                    previous_size: existing.size,
+                })
+            }
+            Some(_) => {}
+        }
+    }
+
+    changes
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::channels::{ChannelChange, diff_channels};
+    use rhiaqey_sdk_rs::channel::Channel;
+
+    fn channel(name: &str, size: usize) -> Channel {
+        Channel {
+            name: name.to_string(),
+            size,
+        }
+    }
+
+    #[test]
+    fn no_changes_for_same_channels() {
+        let channels = vec![channel("a", 10), channel("b", 20)];
+        assert!(diff_channels(channels.as_slice(), channels.as_slice()).is_empty());
+    }
+
+    #[test]
+    fn detects_added_removed_and_resized() {
+        let previous = vec![channel("a", 10), channel("b", 20)];
+        let next = vec![channel("b", 30), channel("c", 10)];
+        let changes = diff_channels(previous.as_slice(), next.as_slice());
+        assert_eq!(changes.len(), 3);
+        assert!(matches!(&changes[0], ChannelChange::Removed(x) if x.name == "a"));
+        assert!(matches!(
+            &changes[1],
+            ChannelChange::Resized { channel, previous_size: 20 } if channel.size == 30
+        ));
+        assert!(matches!(&changes[2], ChannelChange::Added(x) if x.name == "c"));
+    }
+}
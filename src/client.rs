@@ -1,46 +1,249 @@
+use crate::compression::{Compression, CompressionOptions, compress, decompress};
 use crate::stream::StreamMessage;
-use anyhow::Context;
+use anyhow::{Context, bail};
 use rhiaqey_sdk_rs::channel::Channel;
 use rhiaqey_sdk_rs::message::MessageValue;
-use serde::{Deserialize, Serialize};
+use schemars::JsonSchema;
+use serde::de::{self, DeserializeOwned};
+use serde::{Deserialize, Deserializer, Serialize};
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
+/// Protocol version spoken by this crate. Clients that do not send one are
+/// treated as version 1, which only knows about plain json frames.
+pub const CLIENT_PROTOCOL_VERSION: u16 = 2;
+
+/// Server can send `ClientMessageBatch` frames
+pub const CLIENT_CAPABILITY_BATCH: u32 = 1 << 0;
+
+/// Server can send `ClientMessageDataType::Delta` frames
+pub const CLIENT_CAPABILITY_DELTA: u32 = 1 << 1;
+
+fn default_protocol_version() -> u16 {
+    1
+}
+
+fn is_zero(value: &u32) -> bool {
+    *value == 0
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
 pub enum ClientMessageDataType {
     ClientConnection = 0, // sent by the hub to the client with unique client id
     ClientChannelSubscription = 1, // set by the hub to the client when they subscribe to a channel
     Data = 10,            // sent data from hub to client
+    Batch = 11,           // sent many data messages from hub to client in one frame
+    Delta = 12,           // sent json patch against the previous value of the same key
     Ping = 100,           // sent by the hub to keep the client connect alive
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[serde(rename_all = "kebab-case")]
+pub enum ClientEncoding {
+    Json,
+    Msgpack,
+    MsgpackCompact,
+}
+
+impl Display for ClientEncoding {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ClientEncoding::Json => write!(f, "json"),
+            ClientEncoding::Msgpack => write!(f, "msgpack"),
+            ClientEncoding::MsgpackCompact => write!(f, "msgpack-compact"),
+        }
+    }
+}
+
+impl FromStr for ClientEncoding {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "json" => Ok(ClientEncoding::Json),
+            "msgpack" => Ok(ClientEncoding::Msgpack),
+            "msgpack-compact" => Ok(ClientEncoding::MsgpackCompact),
+            _ => bail!("unknown encoding {s}"),
+        }
+    }
+}
+
+/// Wire format of client frames, written as `encoding[+compression]`,
+/// e.g. `json`, `msgpack+zstd`
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[serde(into = "String", try_from = "String")]
+pub struct ClientCodec {
+    pub encoding: ClientEncoding,
+    pub compression: Compression,
+}
+
+impl Default for ClientCodec {
+    fn default() -> Self {
+        ClientCodec::new(ClientEncoding::Json, Compression::None)
+    }
+}
+
+impl ClientCodec {
+    pub fn new(encoding: ClientEncoding, compression: Compression) -> Self {
+        ClientCodec {
+            encoding,
+            compression,
+        }
+    }
+
+    pub fn compression_options(&self, threshold: usize) -> CompressionOptions {
+        CompressionOptions {
+            compression: self.compression,
+            threshold,
+        }
+    }
+
+    /// Parses a comma separated list as advertised by clients.
+    /// Unknown entries are skipped so that newer clients can talk to older hubs.
+    pub fn parse_list(value: &str) -> Vec<ClientCodec> {
+        value
+            .split(',')
+            .filter_map(|x| x.trim().parse::<ClientCodec>().ok())
+            .collect()
+    }
+}
+
+impl Display for ClientCodec {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        if self.compression.is_none() {
+            write!(f, "{}", self.encoding)
+        } else {
+            write!(f, "{}+{}", self.encoding, self.compression)
+        }
+    }
+}
+
+impl FromStr for ClientCodec {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (encoding, compression) = match s.split_once('+') {
+            None => (s, Compression::None),
+            Some((encoding, compression)) => (encoding, compression.parse::<Compression>()?),
+        };
+
+        Ok(ClientCodec::new(
+            encoding.parse::<ClientEncoding>()?,
+            compression,
+        ))
+    }
+}
+
+impl From<ClientCodec> for String {
+    fn from(value: ClientCodec) -> Self {
+        value.to_string()
+    }
+}
+
+impl TryFrom<String> for ClientCodec {
+    type Error = anyhow::Error;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse::<ClientCodec>()
+    }
+}
+
+/// Picks the first codec in the server's preference order that the client also
+/// advertised. Clients that advertise nothing get plain json.
+pub fn negotiate_codec(server: &[ClientCodec], client: &[ClientCodec]) -> Option<ClientCodec> {
+    if client.is_empty() {
+        return Some(ClientCodec::default());
+    }
+
+    server.iter().find(|x| client.contains(x)).copied()
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq)]
 pub struct ClientMessageValueClientConnection {
     pub client_id: String,
     pub hub_id: String,
+
+    #[serde(default = "default_protocol_version")]
+    pub protocol_version: u16,
+
+    // codec the hub picked for this connection
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub codec: Option<ClientCodec>,
+
+    // all codecs the hub can speak
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub codecs: Vec<ClientCodec>,
+
+    // CLIENT_CAPABILITY_* flags
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub capabilities: u32,
+}
+
+impl ClientMessageValueClientConnection {
+    pub fn new(client_id: String, hub_id: String) -> Self {
+        ClientMessageValueClientConnection {
+            client_id,
+            hub_id,
+            protocol_version: CLIENT_PROTOCOL_VERSION,
+            codec: None,
+            codecs: vec![],
+            capabilities: 0,
+        }
+    }
+
+    pub fn has_capability(&self, capability: u32) -> bool {
+        self.capabilities & capability == capability
+    }
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq)]
 pub struct ClientMessageValueClientChannelSubscription {
+    #[schemars(with = "serde_json::Value")]
     pub channel: Channel,
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+/// RFC 6902 patch for a json value. Versions are the timestamps of the
+/// stream messages the value was taken from.
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq)]
+pub struct ClientMessageValueDelta {
+    // version of the value this patch applies to
+    pub base: u64,
+    // version of the value once patched
+    pub version: u64,
+    pub patch: json_patch::Patch,
+}
+
+// deserialized through `ClientMessage`, which picks the variant from the message's
+// data type
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq)]
 #[serde(untagged)]
 pub enum ClientMessageValue {
     ClientConnection(ClientMessageValueClientConnection),
     ClientChannelSubscription(ClientMessageValueClientChannelSubscription),
-    Data(MessageValue),
+    Delta(ClientMessageValueDelta),
+    Data(#[schemars(with = "serde_json::Value")] MessageValue),
     Ping(u64),
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
+#[derive(Serialize, JsonSchema, Clone, Debug)]
 pub struct ClientMessage {
     #[serde(rename = "d", alias = "typ")]
     pub data_type: u8,
 
-    #[serde(rename = "c", alias = "chn", skip_serializing_if = "String::is_empty")]
+    #[serde(
+        rename = "c",
+        alias = "chn",
+        default,
+        skip_serializing_if = "String::is_empty"
+    )]
     pub channel: String,
 
-    #[serde(rename = "k", alias = "key", skip_serializing_if = "String::is_empty")]
+    #[serde(
+        rename = "k",
+        alias = "key",
+        default,
+        skip_serializing_if = "String::is_empty"
+    )]
     pub key: String,
 
     #[serde(rename = "v", alias = "val")]
@@ -60,6 +263,92 @@ pub struct ClientMessage {
     // gateway or producer id, useful for debugging
     #[serde(rename = "p", alias = "pid", skip_serializing_if = "Option::is_none")]
     pub publisher_id: Option<String>,
+
+    // version of the value, used as the base for deltas
+    #[serde(
+        rename = "s",
+        alias = "tms",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub timestamp: Option<u64>,
+}
+
+// wire form of `ClientMessage`, the value is resolved once the data type is known so that
+// user data shaped like another kind of value stays data
+#[derive(Deserialize)]
+struct ClientMessageRepr {
+    #[serde(rename = "d", alias = "typ")]
+    data_type: u8,
+
+    #[serde(rename = "c", alias = "chn", default)]
+    channel: String,
+
+    #[serde(rename = "k", alias = "key", default)]
+    key: String,
+
+    #[serde(rename = "v", alias = "val")]
+    value: MessageValue,
+
+    #[serde(rename = "t", alias = "tag")]
+    tag: Option<String>,
+
+    #[serde(rename = "g", alias = "cat")]
+    category: Option<String>,
+
+    #[serde(rename = "h", alias = "hid")]
+    hub_id: Option<String>,
+
+    #[serde(rename = "p", alias = "pid")]
+    publisher_id: Option<String>,
+
+    #[serde(rename = "s", alias = "tms", default)]
+    timestamp: Option<u64>,
+}
+
+// values other than data are always structured, so they arrive as json
+fn decode_value<T: DeserializeOwned, E: de::Error>(value: MessageValue) -> Result<T, E> {
+    match value {
+        MessageValue::Json(value) => serde_json::from_value(value).map_err(E::custom),
+        _ => Err(E::custom("expected a structured value for this data type")),
+    }
+}
+
+impl<'de> Deserialize<'de> for ClientMessage {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let repr = ClientMessageRepr::deserialize(deserializer)?;
+
+        let value = match repr.data_type {
+            data_type if data_type == ClientMessageDataType::ClientConnection as u8 => {
+                ClientMessageValue::ClientConnection(decode_value(repr.value)?)
+            }
+            data_type if data_type == ClientMessageDataType::ClientChannelSubscription as u8 => {
+                ClientMessageValue::ClientChannelSubscription(decode_value(repr.value)?)
+            }
+            data_type if data_type == ClientMessageDataType::Delta as u8 => {
+                ClientMessageValue::Delta(decode_value(repr.value)?)
+            }
+            data_type if data_type == ClientMessageDataType::Ping as u8 => {
+                ClientMessageValue::Ping(decode_value(repr.value)?)
+            }
+            _ => ClientMessageValue::Data(repr.value),
+        };
+
+        Ok(ClientMessage {
+            data_type: repr.data_type,
+            channel: repr.channel,
+            key: repr.key,
+            value,
+            tag: repr.tag,
+            category: repr.category,
+            hub_id: repr.hub_id,
+            publisher_id: repr.publisher_id,
+            timestamp: repr.timestamp,
+        })
+    }
 }
 
 impl From<StreamMessage> for ClientMessage {
@@ -73,6 +362,7 @@ impl From<StreamMessage> for ClientMessage {
             category: value.category,
             hub_id: value.hub_id,
             publisher_id: value.publisher_id,
+            timestamp: value.timestamp,
         }
     }
 }
@@ -88,11 +378,96 @@ impl From<&StreamMessage> for ClientMessage {
             category: value.category.clone(),
             hub_id: value.hub_id.clone(),
             publisher_id: value.publisher_id.clone(),
+            timestamp: value.timestamp,
         }
     }
 }
 
 impl ClientMessage {
+    /// Builds a delta message that turns `previous` into `next`.
+    /// Returns `None` when the full message should be sent instead, i.e. when the
+    /// messages do not share a key, values are not json, versions are missing or
+    /// the patch would not be smaller than the value itself.
+    pub fn delta(previous: &StreamMessage, next: &StreamMessage) -> Option<ClientMessage> {
+        if previous.channel != next.channel
+            || previous.key != next.key
+            || previous.category != next.category
+        {
+            return None;
+        }
+
+        let (MessageValue::Json(previous_value), MessageValue::Json(next_value)) =
+            (&previous.value, &next.value)
+        else {
+            return None;
+        };
+
+        let (Some(base), Some(version)) = (previous.timestamp, next.timestamp) else {
+            return None;
+        };
+
+        if version <= base {
+            return None;
+        }
+
+        let patch = json_patch::diff(previous_value, next_value);
+
+        let patch_size = serde_json::to_vec(&patch).ok()?.len();
+        let value_size = serde_json::to_vec(next_value).ok()?.len();
+        if patch_size >= value_size {
+            return None;
+        }
+
+        let mut message = ClientMessage::from(next);
+        message.data_type = ClientMessageDataType::Delta as u8;
+        message.value = ClientMessageValue::Delta(ClientMessageValueDelta {
+            base,
+            version,
+            patch,
+        });
+
+        Some(message)
+    }
+
+    /// Client side. Applies a delta message on top of the current full message and
+    /// returns the new full message. Fails if the delta was not built on top of
+    /// the current version.
+    pub fn apply_delta(&self, delta: &ClientMessage) -> anyhow::Result<ClientMessage> {
+        let ClientMessageValue::Delta(value) = &delta.value else {
+            bail!("message is not a delta");
+        };
+
+        if self.channel != delta.channel || self.key != delta.key || self.category != delta.category
+        {
+            bail!("delta does not belong to the same channel, key and category");
+        }
+
+        if self.timestamp != Some(value.base) {
+            bail!(
+                "delta base version {} does not match current version {:?}",
+                value.base,
+                self.timestamp
+            );
+        }
+
+        let ClientMessageValue::Data(MessageValue::Json(current)) = &self.value else {
+            bail!("delta can only be applied to json data");
+        };
+
+        let mut document = current.clone();
+        json_patch::patch(&mut document, &value.patch).context("failed to apply delta")?;
+
+        Ok(ClientMessage {
+            data_type: ClientMessageDataType::Data as u8,
+            value: ClientMessageValue::Data(MessageValue::Json(document)),
+            tag: delta.tag.clone(),
+            hub_id: delta.hub_id.clone(),
+            publisher_id: delta.publisher_id.clone(),
+            timestamp: Some(value.version),
+            ..self.clone()
+        })
+    }
+
     pub fn ser_to_json(&self) -> anyhow::Result<Vec<u8>> {
         serde_json::to_vec(self).context("failed to serialize to json")
     }
@@ -104,11 +479,220 @@ impl ClientMessage {
     pub fn ser_to_msgpack(&self) -> anyhow::Result<Vec<u8>> {
         rmp_serde::to_vec_named(self).context("failed to serialize to msgpack")
     }
+
+    /// Positional msgpack form. Every field is written in declaration order so
+    /// that field names are left out of the frame entirely.
+    pub fn ser_to_msgpack_compact(&self) -> anyhow::Result<Vec<u8>> {
+        rmp_serde::to_vec(&self.compact()).context("failed to serialize to compact msgpack")
+    }
+
+    fn compact(&self) -> impl Serialize + '_ {
+        (
+            self.data_type,
+            &self.channel,
+            &self.key,
+            &self.value,
+            &self.tag,
+            &self.category,
+            &self.hub_id,
+            &self.publisher_id,
+            self.timestamp,
+        )
+    }
+
+    pub fn ser_with_encoding(&self, encoding: ClientEncoding) -> anyhow::Result<Vec<u8>> {
+        match encoding {
+            ClientEncoding::Json => self.ser_to_json(),
+            ClientEncoding::Msgpack => self.ser_to_msgpack(),
+            ClientEncoding::MsgpackCompact => self.ser_to_msgpack_compact(),
+        }
+    }
+
+    /// Serializes with the codec's encoding and compresses frames above `threshold`
+    pub fn ser_with_codec(&self, codec: ClientCodec, threshold: usize) -> anyhow::Result<Vec<u8>> {
+        let data = self.ser_with_encoding(codec.encoding)?;
+        compress(data.as_slice(), codec.compression_options(threshold))
+    }
+
+    pub fn der_with_codec(message: &[u8], codec: ClientCodec) -> anyhow::Result<ClientMessage> {
+        let data = decompress(message).context("failed to decompress message")?;
+        Self::der_with_encoding(data.as_slice(), codec.encoding)
+    }
+
+    pub fn ser_to_json_compressed(&self, options: CompressionOptions) -> anyhow::Result<Vec<u8>> {
+        compress(self.ser_to_json()?.as_slice(), options)
+    }
+
+    pub fn ser_to_msgpack_compressed(
+        &self,
+        options: CompressionOptions,
+    ) -> anyhow::Result<Vec<u8>> {
+        compress(self.ser_to_msgpack()?.as_slice(), options)
+    }
+
+    pub fn der_with_encoding(
+        message: &[u8],
+        encoding: ClientEncoding,
+    ) -> anyhow::Result<ClientMessage> {
+        match encoding {
+            ClientEncoding::Json => Self::der_from_json(message),
+            ClientEncoding::Msgpack | ClientEncoding::MsgpackCompact => {
+                Self::der_from_msgpack(message)
+            }
+        }
+    }
+
+    pub fn der_from_json(message: &[u8]) -> anyhow::Result<ClientMessage> {
+        serde_json::from_slice::<ClientMessage>(message).context("failed to deserialize from json")
+    }
+
+    pub fn der_from_json_str(message: &str) -> anyhow::Result<ClientMessage> {
+        serde_json::from_str::<ClientMessage>(message)
+            .context("failed to deserialize from json string")
+    }
+
+    /// Accepts both the named and the compact msgpack forms
+    pub fn der_from_msgpack(message: &[u8]) -> anyhow::Result<ClientMessage> {
+        rmp_serde::from_slice::<ClientMessage>(message)
+            .context("failed to deserialize from msgpack")
+    }
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
+pub struct ClientMessageBatch {
+    #[serde(rename = "d", alias = "typ")]
+    pub data_type: u8,
+
+    #[serde(rename = "b", alias = "bat")]
+    pub messages: Vec<ClientMessage>,
+}
+
+impl Default for ClientMessageBatch {
+    fn default() -> Self {
+        ClientMessageBatch {
+            data_type: ClientMessageDataType::Batch as u8,
+            messages: vec![],
+        }
+    }
+}
+
+impl From<Vec<ClientMessage>> for ClientMessageBatch {
+    fn from(value: Vec<ClientMessage>) -> Self {
+        ClientMessageBatch {
+            data_type: ClientMessageDataType::Batch as u8,
+            messages: value,
+        }
+    }
+}
+
+impl From<Vec<StreamMessage>> for ClientMessageBatch {
+    fn from(value: Vec<StreamMessage>) -> Self {
+        Self::from_stream_messages(value.as_slice())
+    }
+}
+
+impl From<&[StreamMessage]> for ClientMessageBatch {
+    fn from(value: &[StreamMessage]) -> Self {
+        Self::from_stream_messages(value)
+    }
+}
+
+impl ClientMessageBatch {
+    pub fn from_stream_messages(messages: &[StreamMessage]) -> Self {
+        messages
+            .iter()
+            .map(ClientMessage::from)
+            .collect::<Vec<_>>()
+            .into()
+    }
+
+    /// Splits stream messages into batches of at most `max_size` messages each
+    pub fn chunks_from_stream_messages(messages: &[StreamMessage], max_size: usize) -> Vec<Self> {
+        messages
+            .chunks(max_size.max(1))
+            .map(Self::from_stream_messages)
+            .collect()
+    }
+
+    pub fn push(&mut self, message: impl Into<ClientMessage>) {
+        self.messages.push(message.into());
+    }
+
+    pub fn len(&self) -> usize {
+        self.messages.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.messages.is_empty()
+    }
+
+    pub fn ser_to_json(&self) -> anyhow::Result<Vec<u8>> {
+        serde_json::to_vec(self).context("failed to serialize batch to json")
+    }
+
+    pub fn ser_to_json_str(&self) -> anyhow::Result<String> {
+        serde_json::to_string(self).context("failed to serialize batch to json string")
+    }
+
+    pub fn ser_to_msgpack(&self) -> anyhow::Result<Vec<u8>> {
+        rmp_serde::to_vec_named(self).context("failed to serialize batch to msgpack")
+    }
+
+    /// Positional msgpack form, the data type followed by the compact form of each message
+    pub fn ser_to_msgpack_compact(&self) -> anyhow::Result<Vec<u8>> {
+        let messages: Vec<_> = self.messages.iter().map(ClientMessage::compact).collect();
+        rmp_serde::to_vec(&(self.data_type, messages))
+            .context("failed to serialize batch to compact msgpack")
+    }
+
+    pub fn ser_with_encoding(&self, encoding: ClientEncoding) -> anyhow::Result<Vec<u8>> {
+        match encoding {
+            ClientEncoding::Json => self.ser_to_json(),
+            ClientEncoding::Msgpack => self.ser_to_msgpack(),
+            ClientEncoding::MsgpackCompact => self.ser_to_msgpack_compact(),
+        }
+    }
+
+    pub fn ser_with_codec(&self, codec: ClientCodec, threshold: usize) -> anyhow::Result<Vec<u8>> {
+        let data = self.ser_with_encoding(codec.encoding)?;
+        compress(data.as_slice(), codec.compression_options(threshold))
+    }
+
+    pub fn der_with_codec(message: &[u8], codec: ClientCodec) -> anyhow::Result<Self> {
+        let data = decompress(message).context("failed to decompress batch")?;
+        Self::der_with_encoding(data.as_slice(), codec.encoding)
+    }
+
+    pub fn der_with_encoding(message: &[u8], encoding: ClientEncoding) -> anyhow::Result<Self> {
+        match encoding {
+            ClientEncoding::Json => Self::der_from_json(message),
+            ClientEncoding::Msgpack | ClientEncoding::MsgpackCompact => {
+                Self::der_from_msgpack(message)
+            }
+        }
+    }
+
+    pub fn der_from_json(message: &[u8]) -> anyhow::Result<Self> {
+        serde_json::from_slice::<Self>(message).context("failed to deserialize batch from json")
+    }
+
+    /// Accepts both the named and the compact msgpack forms
+    pub fn der_from_msgpack(message: &[u8]) -> anyhow::Result<Self> {
+        rmp_serde::from_slice::<Self>(message).context("failed to deserialize batch from msgpack")
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::client::{ClientMessage, ClientMessageDataType, ClientMessageValue};
+    use crate::client::{
+        CLIENT_CAPABILITY_BATCH, ClientCodec, ClientEncoding, ClientMessage, ClientMessageBatch,
+        ClientMessageDataType, ClientMessageValue, ClientMessageValueClientConnection,
+        negotiate_codec,
+    };
+    use crate::compression::Compression;
+    use crate::stream::StreamMessage;
+    use crate::stream::tests::{arb_stream_message, stream_message};
+    use proptest::prelude::*;
     use rhiaqey_sdk_rs::message::MessageValue;
 
     #[test]
@@ -122,6 +706,7 @@ mod tests {
             category: None,
             hub_id: None,
             publisher_id: None,
+            timestamp: None,
         };
 
         let serialized = serde_json::to_string(&client_message).unwrap_or_default();
@@ -167,4 +752,311 @@ mod tests {
         assert_eq!(client_message.hub_id, None);
         assert_eq!(client_message.publisher_id, None);
     }
+
+    #[test]
+    fn can_serialize_batch() {
+        let batch =
+            ClientMessageBatch::from(vec![stream_message("key_1"), stream_message("key_2")]);
+        assert_eq!(batch.len(), 2);
+        assert_eq!(batch.data_type, ClientMessageDataType::Batch as u8);
+
+        let serialized = batch.ser_to_json_str().unwrap();
+        assert!(serialized.starts_with("{\"d\":11,\"b\":["));
+        assert!(serialized.contains("\"key_1\""));
+        assert!(serialized.contains("\"key_2\""));
+    }
+
+    #[test]
+    fn can_deserialize_batch() {
+        let serialized_message =
+            "{\"d\":11,\"b\":[{\"d\":10,\"c\":\"channel_1\",\"k\":\"key_1\",\"v\":\"some text\"}]}";
+        let batch = serde_json::from_str::<ClientMessageBatch>(serialized_message).unwrap();
+        assert_eq!(batch.data_type, ClientMessageDataType::Batch as u8);
+        assert_eq!(batch.len(), 1);
+        assert_eq!(batch.messages[0].key, "key_1");
+    }
+
+    #[test]
+    fn can_chunk_batches() {
+        let messages = (0..5)
+            .map(|i| stream_message(format!("key_{i}").as_str()))
+            .collect::<Vec<_>>();
+        let batches = ClientMessageBatch::chunks_from_stream_messages(messages.as_slice(), 2);
+        assert_eq!(batches.len(), 3);
+        assert_eq!(batches[2].len(), 1);
+        assert!(!batches[0].ser_to_msgpack().unwrap().is_empty());
+    }
+
+    fn assert_same(left: &ClientMessage, right: &ClientMessage) {
+        assert_eq!(left.data_type, right.data_type);
+        assert_eq!(left.channel, right.channel);
+        assert_eq!(left.key, right.key);
+        assert_eq!(left.value, right.value);
+        assert_eq!(left.tag, right.tag);
+        assert_eq!(left.category, right.category);
+        assert_eq!(left.hub_id, right.hub_id);
+        assert_eq!(left.publisher_id, right.publisher_id);
+        assert_eq!(left.timestamp, right.timestamp);
+    }
+
+    proptest! {
+        #[test]
+        fn json_round_trip(message in arb_stream_message()) {
+            let client_message = ClientMessage::from(message);
+            let serialized = client_message.ser_to_json().unwrap();
+            assert_same(&ClientMessage::der_from_json(serialized.as_slice()).unwrap(), &client_message);
+        }
+
+        #[test]
+        fn msgpack_round_trip(message in arb_stream_message()) {
+            let client_message = ClientMessage::from(message);
+            let serialized = client_message.ser_to_msgpack().unwrap();
+            assert_same(&ClientMessage::der_from_msgpack(serialized.as_slice()).unwrap(), &client_message);
+        }
+
+        #[test]
+        fn compact_msgpack_round_trip(message in arb_stream_message()) {
+            let client_message = ClientMessage::from(message);
+            let serialized = client_message.ser_to_msgpack_compact().unwrap();
+            assert_same(&ClientMessage::der_from_msgpack(serialized.as_slice()).unwrap(), &client_message);
+        }
+
+        #[test]
+        fn batch_round_trip(messages in proptest::collection::vec(arb_stream_message(), 0..4)) {
+            let batch = ClientMessageBatch::from(messages);
+            for encoding in [ClientEncoding::Json, ClientEncoding::Msgpack, ClientEncoding::MsgpackCompact] {
+                let codec = ClientCodec::new(encoding, Compression::Zstd);
+                let serialized = batch.ser_with_codec(codec, 0).unwrap();
+                let deserialized = ClientMessageBatch::der_with_codec(serialized.as_slice(), codec).unwrap();
+                assert_eq!(deserialized.data_type, batch.data_type);
+                assert_eq!(deserialized.len(), batch.len());
+                for (left, right) in deserialized.messages.iter().zip(batch.messages.iter()) {
+                    assert_same(left, right);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn codec_can_be_parsed() {
+        let codecs = ClientCodec::parse_list("msgpack+zstd, json,brotli,msgpack-compact+lz4");
+        assert_eq!(
+            codecs,
+            vec![
+                ClientCodec::new(ClientEncoding::Msgpack, Compression::Zstd),
+                ClientCodec::new(ClientEncoding::Json, Compression::None),
+                ClientCodec::new(ClientEncoding::MsgpackCompact, Compression::Lz4),
+            ]
+        );
+        assert_eq!(codecs[0].to_string(), "msgpack+zstd");
+        assert_eq!(codecs[1].to_string(), "json");
+    }
+
+    #[test]
+    fn negotiate_picks_server_preference() {
+        let server = ClientCodec::parse_list("msgpack+zstd,msgpack,json+deflate,json");
+        let client = ClientCodec::parse_list("json,msgpack");
+        assert_eq!(
+            negotiate_codec(server.as_slice(), client.as_slice()),
+            Some(ClientCodec::new(ClientEncoding::Msgpack, Compression::None))
+        );
+    }
+
+    #[test]
+    fn negotiate_falls_back_to_json_for_legacy_clients() {
+        let server = ClientCodec::parse_list("msgpack,json");
+        assert_eq!(
+            negotiate_codec(server.as_slice(), &[]),
+            Some(ClientCodec::default())
+        );
+        let client = ClientCodec::parse_list("msgpack+lz4");
+        assert_eq!(negotiate_codec(server.as_slice(), client.as_slice()), None);
+    }
+
+    #[test]
+    fn legacy_connection_message_can_be_deserialized() {
+        let serialized_message = "{\"d\":0,\"v\":{\"client_id\":\"c1\",\"hub_id\":\"h1\"}}";
+        let client_message = ClientMessage::der_from_json_str(serialized_message).unwrap();
+        let ClientMessageValue::ClientConnection(connection) = client_message.value else {
+            panic!("expected a client connection value");
+        };
+        assert_eq!(connection.protocol_version, 1);
+        assert_eq!(connection.codec, None);
+        assert!(connection.codecs.is_empty());
+        assert_eq!(connection.capabilities, 0);
+    }
+
+    #[test]
+    fn connection_message_carries_codecs() {
+        let mut connection =
+            ClientMessageValueClientConnection::new("c1".to_string(), "h1".to_string());
+        connection.codec = Some(ClientCodec::new(ClientEncoding::Json, Compression::Zstd));
+        connection.codecs = ClientCodec::parse_list("json+zstd,json");
+        connection.capabilities = CLIENT_CAPABILITY_BATCH;
+
+        let serialized = serde_json::to_string(&connection).unwrap();
+        assert!(serialized.contains("\"codec\":\"json+zstd\""));
+
+        let deserialized =
+            serde_json::from_str::<ClientMessageValueClientConnection>(serialized.as_str())
+                .unwrap();
+        assert_eq!(deserialized, connection);
+        assert!(deserialized.has_capability(CLIENT_CAPABILITY_BATCH));
+    }
+
+    #[test]
+    fn can_round_trip_with_codec() {
+        let mut message = stream_message("key_1");
+        message.value = MessageValue::Text("some text".repeat(500));
+        let client_message = ClientMessage::from(message);
+
+        for codec in ClientCodec::parse_list("json+zstd,msgpack+lz4,msgpack-compact+deflate") {
+            let serialized = client_message.ser_with_codec(codec, 128).unwrap();
+            assert!(crate::compression::is_compressed(serialized.as_slice()));
+            let deserialized = ClientMessage::der_with_codec(serialized.as_slice(), codec).unwrap();
+            assert_same(&deserialized, &client_message);
+        }
+    }
+
+    fn json_message(timestamp: u64, value: serde_json::Value) -> StreamMessage {
+        let mut message = stream_message("key_1");
+        message.timestamp = Some(timestamp);
+        message.value = MessageValue::Json(value);
+        message
+    }
+
+    fn large_document(price: u64) -> serde_json::Value {
+        let mut document = serde_json::json!({ "price": price });
+        for i in 0..20 {
+            document[format!("field_{i}")] = serde_json::json!("unchanged value");
+        }
+        document
+    }
+
+    #[test]
+    fn delta_can_be_applied() {
+        let previous = json_message(1, large_document(10));
+        let next = json_message(2, large_document(11));
+
+        let delta = ClientMessage::delta(&previous, &next).unwrap();
+        assert_eq!(delta.data_type, ClientMessageDataType::Delta as u8);
+
+        let serialized = delta.ser_to_json().unwrap();
+        let delta = ClientMessage::der_from_json(serialized.as_slice()).unwrap();
+
+        let current = ClientMessage::from(&previous);
+        let updated = current.apply_delta(&delta).unwrap();
+        assert_same(&updated, &ClientMessage::from(&next));
+    }
+
+    #[test]
+    fn delta_round_trips_in_every_encoding() {
+        let previous = json_message(1, large_document(10));
+        let next = json_message(2, large_document(11));
+        let delta = ClientMessage::delta(&previous, &next).unwrap();
+
+        for encoding in [
+            ClientEncoding::Json,
+            ClientEncoding::Msgpack,
+            ClientEncoding::MsgpackCompact,
+        ] {
+            let serialized = delta.ser_with_encoding(encoding).unwrap();
+            let deserialized =
+                ClientMessage::der_with_encoding(serialized.as_slice(), encoding).unwrap();
+            assert_same(&deserialized, &delta);
+        }
+    }
+
+    #[test]
+    fn data_shaped_like_a_delta_stays_data() {
+        let value = serde_json::json!({ "base": 1, "version": 2, "patch": [] });
+        let message = ClientMessage::from(json_message(2, value.clone()));
+
+        for encoding in [
+            ClientEncoding::Json,
+            ClientEncoding::Msgpack,
+            ClientEncoding::MsgpackCompact,
+        ] {
+            let serialized = message.ser_with_encoding(encoding).unwrap();
+            let deserialized =
+                ClientMessage::der_with_encoding(serialized.as_slice(), encoding).unwrap();
+            assert_eq!(
+                deserialized.value,
+                ClientMessageValue::Data(MessageValue::Json(value.clone()))
+            );
+        }
+    }
+
+    #[test]
+    fn data_shaped_like_a_connection_stays_data() {
+        let value = serde_json::json!({ "client_id": "c1", "hub_id": "h1" });
+        let serialized = serde_json::json!({ "d": 10, "v": value }).to_string();
+        let deserialized = ClientMessage::der_from_json_str(serialized.as_str()).unwrap();
+        assert_eq!(
+            deserialized.value,
+            ClientMessageValue::Data(MessageValue::Json(value))
+        );
+    }
+
+    #[test]
+    fn values_are_decoded_by_data_type() {
+        let mut connection = ClientMessage::from(stream_message("key_1"));
+        connection.data_type = ClientMessageDataType::ClientConnection as u8;
+        connection.value = ClientMessageValue::ClientConnection(
+            ClientMessageValueClientConnection::new("c1".to_string(), "h1".to_string()),
+        );
+
+        let mut ping = ClientMessage::from(stream_message("key_1"));
+        ping.data_type = ClientMessageDataType::Ping as u8;
+        ping.value = ClientMessageValue::Ping(42);
+
+        for message in [connection, ping] {
+            for encoding in [
+                ClientEncoding::Json,
+                ClientEncoding::Msgpack,
+                ClientEncoding::MsgpackCompact,
+            ] {
+                let serialized = message.ser_with_encoding(encoding).unwrap();
+                let deserialized =
+                    ClientMessage::der_with_encoding(serialized.as_slice(), encoding).unwrap();
+                assert_eq!(deserialized.value, message.value, "{encoding}");
+            }
+        }
+    }
+
+    #[test]
+    fn delta_message_requires_a_delta_value() {
+        let serialized = r#"{"d":12,"v":"some text"}"#;
+        assert!(ClientMessage::der_from_json_str(serialized).is_err());
+    }
+
+    #[test]
+    fn missing_timestamp_is_not_serialized() {
+        let message = ClientMessage::from(stream_message("key_1"));
+        let serialized = message.ser_to_json_str().unwrap();
+        assert!(!serialized.contains("\"s\""));
+    }
+
+    #[test]
+    fn delta_requires_matching_base_version() {
+        let previous = json_message(1, large_document(10));
+        let next = json_message(2, large_document(11));
+        let delta = ClientMessage::delta(&previous, &next).unwrap();
+
+        let stale = ClientMessage::from(&json_message(3, large_document(10)));
+        assert!(stale.apply_delta(&delta).is_err());
+    }
+
+    #[test]
+    fn delta_is_skipped_when_not_useful() {
+        let previous = json_message(1, serde_json::json!({ "price": 10 }));
+        let next = json_message(2, serde_json::json!({ "price": 11 }));
+        assert!(ClientMessage::delta(&previous, &next).is_none());
+
+        let previous = json_message(2, large_document(10));
+        let next = json_message(1, large_document(11));
+        assert!(ClientMessage::delta(&previous, &next).is_none());
+
+        assert!(ClientMessage::delta(&stream_message("key_1"), &stream_message("key_1")).is_none());
+    }
 }
@@ -0,0 +1,20 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Milliseconds since the unix epoch, `0` if the system clock is set before it
+pub fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::clock::now_millis;
+
+    #[test]
+    fn now_is_after_the_epoch() {
+        // 2020-01-01T00:00:00Z
+        assert!(now_millis() > 1_577_836_800_000);
+    }
+}
@@ -0,0 +1,206 @@
+use anyhow::{Context, bail};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::fmt::{Display, Formatter};
+use std::io::{Read, Write};
+use std::str::FromStr;
+
+/// First byte of every compressed frame. 0xc1 is never used by msgpack and
+/// is not valid utf-8, so it cannot be mistaken for an uncompressed frame.
+pub const COMPRESSION_MAGIC: u8 = 0xc1;
+
+/// Frames smaller than this are sent as they are
+pub const DEFAULT_COMPRESSION_THRESHOLD: usize = 1024;
+
+#[derive(Serialize, Deserialize, JsonSchema, Default, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    #[default]
+    None,
+    Deflate,
+    Lz4,
+    Zstd,
+}
+
+impl Compression {
+    pub fn is_none(&self) -> bool {
+        *self == Compression::None
+    }
+
+    fn id(&self) -> u8 {
+        match self {
+            Compression::None => 0,
+            Compression::Deflate => 1,
+            Compression::Lz4 => 2,
+            Compression::Zstd => 3,
+        }
+    }
+
+    fn from_id(id: u8) -> anyhow::Result<Self> {
+        match id {
+            0 => Ok(Compression::None),
+            1 => Ok(Compression::Deflate),
+            2 => Ok(Compression::Lz4),
+            3 => Ok(Compression::Zstd),
+            _ => bail!("unknown compression id {id}"),
+        }
+    }
+}
+
+impl Display for Compression {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Compression::None => write!(f, "none"),
+            Compression::Deflate => write!(f, "deflate"),
+            Compression::Lz4 => write!(f, "lz4"),
+            Compression::Zstd => write!(f, "zstd"),
+        }
+    }
+}
+
+impl FromStr for Compression {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Compression::None),
+            "deflate" => Ok(Compression::Deflate),
+            "lz4" => Ok(Compression::Lz4),
+            "zstd" => Ok(Compression::Zstd),
+            _ => bail!("unknown compression {s}"),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CompressionOptions {
+    pub compression: Compression,
+    pub threshold: usize,
+}
+
+impl Default for CompressionOptions {
+    fn default() -> Self {
+        CompressionOptions {
+            compression: Compression::None,
+            threshold: DEFAULT_COMPRESSION_THRESHOLD,
+        }
+    }
+}
+
+impl From<Compression> for CompressionOptions {
+    fn from(value: Compression) -> Self {
+        CompressionOptions {
+            compression: value,
+            ..Default::default()
+        }
+    }
+}
+
+pub fn is_compressed(data: &[u8]) -> bool {
+    data.first() == Some(&COMPRESSION_MAGIC)
+}
+
+/// Compresses a frame and prefixes it with a two byte header naming the codec.
+/// Data below the threshold, or data that does not shrink, is returned untouched.
+pub fn compress(data: &[u8], options: CompressionOptions) -> anyhow::Result<Vec<u8>> {
+    if options.compression.is_none() || data.len() < options.threshold {
+        return Ok(data.to_vec());
+    }
+
+    let mut frame = vec![COMPRESSION_MAGIC, options.compression.id()];
+
+    match options.compression {
+        Compression::None => unreachable!(),
+        Compression::Deflate => {
+            let mut encoder =
+                flate2::write::DeflateEncoder::new(frame, flate2::Compression::default());
+            encoder.write_all(data).context("failed to deflate data")?;
+            frame = encoder.finish().context("failed to finish deflate")?;
+        }
+        Compression::Lz4 => {
+            frame.extend(lz4_flex::compress_prepend_size(data));
+        }
+        Compression::Zstd => {
+            frame.extend(zstd::encode_all(data, 0).context("failed to zstd data")?);
+        }
+    }
+
+    if frame.len() >= data.len() {
+        return Ok(data.to_vec());
+    }
+
+    Ok(frame)
+}
+
+/// Reverses `compress`. Frames without a compression header are returned untouched.
+pub fn decompress(data: &[u8]) -> anyhow::Result<Vec<u8>> {
+    if !is_compressed(data) {
+        return Ok(data.to_vec());
+    }
+
+    let Some(id) = data.get(1) else {
+        bail!("compressed frame is missing its codec");
+    };
+
+    let payload = &data[2..];
+
+    match Compression::from_id(*id)? {
+        Compression::None => Ok(payload.to_vec()),
+        Compression::Deflate => {
+            let mut result = vec![];
+            flate2::read::DeflateDecoder::new(payload)
+                .read_to_end(&mut result)
+                .context("failed to inflate data")?;
+            Ok(result)
+        }
+        Compression::Lz4 => {
+            lz4_flex::decompress_size_prepended(payload).context("failed to decompress lz4 data")
+        }
+        Compression::Zstd => zstd::decode_all(payload).context("failed to decompress zstd data"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::compression::{
+        Compression, CompressionOptions, compress, decompress, is_compressed,
+    };
+
+    fn sample() -> Vec<u8> {
+        "{\"key\":\"value\"}".repeat(200).into_bytes()
+    }
+
+    #[test]
+    fn can_round_trip_every_codec() {
+        let data = sample();
+        for compression in [
+            Compression::None,
+            Compression::Deflate,
+            Compression::Lz4,
+            Compression::Zstd,
+        ] {
+            let compressed = compress(data.as_slice(), compression.into()).unwrap();
+            assert_eq!(is_compressed(compressed.as_slice()), !compression.is_none());
+            assert_eq!(decompress(compressed.as_slice()).unwrap(), data);
+        }
+    }
+
+    #[test]
+    fn small_frames_are_not_compressed() {
+        let data = b"{\"key\":\"value\"}";
+        let options = CompressionOptions {
+            compression: Compression::Zstd,
+            threshold: 1024,
+        };
+        let compressed = compress(data, options).unwrap();
+        assert_eq!(compressed.as_slice(), data);
+        assert_eq!(decompress(compressed.as_slice()).unwrap(), data);
+    }
+
+    #[test]
+    fn compression_can_be_parsed() {
+        assert_eq!("zstd".parse::<Compression>().unwrap(), Compression::Zstd);
+        assert_eq!(Compression::Lz4.to_string(), "lz4");
+        assert!("brotli".parse::<Compression>().is_err());
+    }
+}
@@ -0,0 +1,357 @@
+use crate::compression::is_compressed;
+use crate::redis::{RedisSettings, RhiaqeyBufVec, connect_and_ping_async};
+use crate::stream::StreamMessage;
+use crate::topics;
+use anyhow::{Context, bail};
+use log::{debug, info, trace, warn};
+use rustis::client::Client;
+use rustis::commands::{StreamCommands, XAutoClaimOptions, XGroupCreateOptions, XReadGroupOptions};
+use std::collections::HashMap;
+use std::time::Duration;
+
+/// Fields of a raw stream entry as written by `Executor::publish_async`
+pub type StreamFields = HashMap<String, RhiaqeyBufVec>;
+
+type StreamEntries = Vec<(String, StreamFields)>;
+
+#[derive(Clone, Debug)]
+pub struct StreamConsumerOptions {
+    /// Consumer group shared by every consumer that splits the work
+    pub group: String,
+
+    /// Name of this consumer within the group
+    pub consumer: String,
+
+    /// Most entries returned per stream by a single read or claim
+    pub batch_size: usize,
+
+    /// How long a read waits for new entries, `None` returns right away
+    pub block: Option<Duration>,
+
+    /// How long an entry must be pending before another consumer may claim it
+    pub min_idle: Duration,
+
+    /// Where a newly created group starts reading, `0` for everything still in the stream
+    pub start_id: String,
+}
+
+impl StreamConsumerOptions {
+    pub fn new(group: impl Into<String>, consumer: impl Into<String>) -> Self {
+        StreamConsumerOptions {
+            group: group.into(),
+            consumer: consumer.into(),
+            batch_size: 100,
+            block: Some(Duration::from_secs(5)),
+            min_idle: Duration::from_secs(60),
+            start_id: String::from("0"),
+        }
+    }
+}
+
+/// A message read from a channel's raw stream
+#[derive(Clone, Debug)]
+pub struct ConsumedMessage {
+    pub topic: String,
+    pub id: String,
+    pub message: StreamMessage,
+}
+
+/// Turns the `raw`, `tag`, `tms` and `cmp` fields of a stream entry back into a message.
+/// Tag and timestamp are taken from the fields when the payload does not carry them.
+pub fn parse_stream_fields(fields: &StreamFields) -> anyhow::Result<StreamMessage> {
+    let raw = fields.get("raw").context("stream entry has no raw field")?;
+
+    let marked_compressed = fields.get("cmp").is_some_and(|cmp| cmp.0 == b"1");
+    if marked_compressed && !is_compressed(raw.0.as_slice()) {
+        bail!("stream entry is marked compressed but has no compression header");
+    }
+
+    let mut message = StreamMessage::der_from_compressed(raw.0.as_slice())
+        .context("failed to parse raw field")?;
+
+    let field = |name: &str| {
+        fields
+            .get(name)
+            .map(|value| String::from_utf8_lossy(value.0.as_slice()).to_string())
+            .filter(|value| !value.is_empty())
+    };
+
+    if message.tag.is_none() {
+        message.tag = field("tag");
+    }
+
+    if message.timestamp.is_none() {
+        message.timestamp = field("tms")
+            .and_then(|tms| tms.parse::<u64>().ok())
+            .filter(|tms| *tms > 0);
+    }
+
+    Ok(message)
+}
+
+/// Reads channel raw streams through a consumer group on its own connection, so that
+/// blocking reads do not hold up other commands
+pub struct StreamConsumer {
+    client: Client,
+    topics: Vec<String>,
+    options: StreamConsumerOptions,
+}
+
+impl StreamConsumer {
+    pub async fn connect_async(
+        config: RedisSettings,
+        namespace: &str,
+        channels: &[String],
+        options: StreamConsumerOptions,
+    ) -> anyhow::Result<Self> {
+        let client = connect_and_ping_async(config)
+            .await
+            .context("failed to connect and ping async to redis")?;
+
+        let topics = channels
+            .iter()
+            .map(|channel| topics::publishers_to_hub_stream_topic(namespace, channel.as_str()))
+            .collect();
+
+        let consumer = StreamConsumer {
+            client,
+            topics,
+            options,
+        };
+
+        consumer.create_groups_async().await?;
+
+        Ok(consumer)
+    }
+
+    pub fn topics(&self) -> &[String] {
+        self.topics.as_slice()
+    }
+
+    /// Creates the consumer group on every stream, creating missing streams too.
+    /// Groups that already exist are left as they are.
+    pub async fn create_groups_async(&self) -> anyhow::Result<()> {
+        for topic in self.topics.iter() {
+            let result = self
+                .client
+                .xgroup_create(
+                    topic.clone(),
+                    self.options.group.clone(),
+                    self.options.start_id.clone(),
+                    XGroupCreateOptions::default().mk_stream(),
+                )
+                .await;
+
+            match result {
+                Ok(_) => info!("created group {} on {topic}", self.options.group),
+                Err(err) if err.to_string().contains("BUSYGROUP") => {
+                    trace!("group {} already exists on {topic}", self.options.group)
+                }
+                Err(err) => {
+                    return Err(err).context(format!("failed to create group on {topic}"));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Reads entries that were never delivered to this group, waiting up to
+    /// `options.block` for new ones
+    pub async fn read_async(&self) -> anyhow::Result<Vec<ConsumedMessage>> {
+        let mut options = XReadGroupOptions::default().count(self.options.batch_size);
+        if let Some(block) = self.options.block {
+            options = options.block(block.as_millis() as u64);
+        }
+
+        let ids = vec![">"; self.topics.len()];
+
+        let reply: Option<Vec<(String, StreamEntries)>> = self
+            .client
+            .xreadgroup(
+                self.options.group.clone(),
+                self.options.consumer.clone(),
+                options,
+                self.topics.clone(),
+                ids,
+            )
+            .await
+            .context("failed to read from consumer group")?;
+
+        let mut messages = vec![];
+
+        for (topic, entries) in reply.unwrap_or_default() {
+            messages.extend(self.parse_entries(topic.as_str(), entries).await?);
+        }
+
+        trace!("read {} message(s)", messages.len());
+
+        Ok(messages)
+    }
+
+    /// Acknowledges processed messages so they leave the pending entries list
+    pub async fn ack_async(&self, messages: &[ConsumedMessage]) -> anyhow::Result<usize> {
+        let mut ids: HashMap<&str, Vec<&str>> = HashMap::new();
+        for message in messages {
+            ids.entry(message.topic.as_str())
+                .or_default()
+                .push(message.id.as_str());
+        }
+
+        let mut acked = 0;
+        for (topic, ids) in ids {
+            acked += self.ack_ids_async(topic, ids).await?;
+        }
+
+        Ok(acked)
+    }
+
+    async fn ack_ids_async(&self, topic: &str, ids: Vec<&str>) -> anyhow::Result<usize> {
+        let acked: usize = self
+            .client
+            .xack(topic, self.options.group.clone(), ids)
+            .await
+            .context("failed to ack stream entries")?;
+
+        Ok(acked)
+    }
+
+    /// Takes over entries that other consumers left pending for longer than
+    /// `options.min_idle`, e.g. because they crashed while processing them
+    pub async fn claim_stale_async(&self) -> anyhow::Result<Vec<ConsumedMessage>> {
+        let mut messages = vec![];
+
+        for topic in self.topics.iter() {
+            let (_, entries, deleted): (String, StreamEntries, Vec<String>) = self
+                .client
+                .xautoclaim(
+                    topic.clone(),
+                    self.options.group.clone(),
+                    self.options.consumer.clone(),
+                    self.options.min_idle.as_millis() as u64,
+                    "0-0",
+                    XAutoClaimOptions::default().count(self.options.batch_size),
+                )
+                .await
+                .context("failed to claim pending entries")?;
+
+            if !deleted.is_empty() {
+                debug!(
+                    "{} pending entries of {topic} were trimmed before they were processed",
+                    deleted.len()
+                );
+            }
+
+            messages.extend(self.parse_entries(topic.as_str(), entries).await?);
+        }
+
+        if !messages.is_empty() {
+            debug!("claimed {} stale message(s)", messages.len());
+        }
+
+        Ok(messages)
+    }
+
+    /// Parses entries of one stream. Malformed entries can never be processed, so they
+    /// are logged and acknowledged right away instead of staying pending.
+    async fn parse_entries(
+        &self,
+        topic: &str,
+        entries: StreamEntries,
+    ) -> anyhow::Result<Vec<ConsumedMessage>> {
+        let mut messages = vec![];
+        let mut malformed = vec![];
+
+        for (id, fields) in entries.iter() {
+            match parse_stream_fields(fields) {
+                Ok(message) => messages.push(ConsumedMessage {
+                    topic: topic.to_string(),
+                    id: id.clone(),
+                    message,
+                }),
+                Err(err) => {
+                    warn!("dropping malformed entry {id} of {topic}: {err:#}");
+                    malformed.push(id.as_str());
+                }
+            }
+        }
+
+        if !malformed.is_empty() {
+            self.ack_ids_async(topic, malformed).await?;
+        }
+
+        Ok(messages)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::compression::{Compression, CompressionOptions};
+    use crate::consumer::{StreamFields, parse_stream_fields};
+    use crate::redis::RhiaqeyBufVec;
+    use crate::stream::tests::stream_message;
+    use rhiaqey_sdk_rs::message::MessageValue;
+
+    fn fields(raw: Vec<u8>, tag: &str, tms: &str, cmp: &str) -> StreamFields {
+        [
+            ("raw", raw),
+            ("tag", tag.as_bytes().to_vec()),
+            ("tms", tms.as_bytes().to_vec()),
+            ("cmp", cmp.as_bytes().to_vec()),
+        ]
+        .into_iter()
+        .map(|(name, value)| (name.to_string(), RhiaqeyBufVec::from(value)))
+        .collect()
+    }
+
+    #[test]
+    fn parses_plain_entries() {
+        let raw = stream_message("key_1")
+            .ser_to_string()
+            .unwrap()
+            .into_bytes();
+        let parsed = parse_stream_fields(&fields(raw, "", "0", "0")).unwrap();
+        assert_eq!(parsed, stream_message("key_1"));
+    }
+
+    #[test]
+    fn parses_compressed_entries() {
+        let options = CompressionOptions {
+            compression: Compression::Zstd,
+            threshold: 0,
+        };
+        let mut message = stream_message("key_1");
+        message.value = MessageValue::Text("some text ".repeat(100));
+
+        let raw = message.ser_to_compressed(options).unwrap();
+        let parsed = parse_stream_fields(&fields(raw, "", "0", "1")).unwrap();
+        assert_eq!(parsed, message);
+    }
+
+    #[test]
+    fn fills_tag_and_timestamp_from_fields() {
+        let raw = stream_message("key_1")
+            .ser_to_string()
+            .unwrap()
+            .into_bytes();
+        let parsed = parse_stream_fields(&fields(raw, "tag_1", "42", "0")).unwrap();
+        assert_eq!(parsed.tag.as_deref(), Some("tag_1"));
+        assert_eq!(parsed.timestamp, Some(42));
+    }
+
+    #[test]
+    fn rejects_mismatched_compression_flag() {
+        let raw = stream_message("key_1")
+            .ser_to_string()
+            .unwrap()
+            .into_bytes();
+        assert!(parse_stream_fields(&fields(raw, "", "0", "1")).is_err());
+    }
+
+    #[test]
+    fn rejects_entries_without_raw() {
+        let mut fields = fields(vec![], "", "0", "0");
+        fields.remove("raw");
+        assert!(parse_stream_fields(&fields).is_err());
+    }
+}
@@ -0,0 +1,243 @@
+use crate::pubsub::{
+    ClientConnectedMessage, ClientDisconnectedMessage, RPCMessage, RPCMessageData,
+};
+use crate::redis::{RedisSettings, connect_and_ping_async};
+use crate::topics;
+use anyhow::Context;
+use futures_util::StreamExt;
+use futures_util::stream::BoxStream;
+use log::{debug, trace, warn};
+use rhiaqey_sdk_rs::channel::Channel;
+use rustis::client::Client;
+use rustis::commands::PubSubCommands;
+
+/// A client connection event as carried on the event bus
+#[derive(Clone, Debug)]
+pub enum ClientEvent {
+    Connected(ClientConnectedMessage),
+    Disconnected(ClientDisconnectedMessage),
+}
+
+impl ClientEvent {
+    pub fn client_id(&self) -> &str {
+        match self {
+            ClientEvent::Connected(message) => message.client_id.as_str(),
+            ClientEvent::Disconnected(message) => message.client_id.as_str(),
+        }
+    }
+
+    pub fn user_id(&self) -> Option<&str> {
+        match self {
+            ClientEvent::Connected(message) => message.user_id.as_deref(),
+            ClientEvent::Disconnected(message) => message.user_id.as_deref(),
+        }
+    }
+
+    /// Channel subscriptions with their optional category and key
+    pub fn channels(&self) -> &[(Channel, Option<String>, Option<String>)] {
+        match self {
+            ClientEvent::Connected(message) => message.channels.as_slice(),
+            ClientEvent::Disconnected(message) => message.channels.as_slice(),
+        }
+    }
+
+    pub fn is_connected(&self) -> bool {
+        matches!(self, ClientEvent::Connected(_))
+    }
+
+    pub fn from_rpc(data: RPCMessageData) -> Option<ClientEvent> {
+        match data {
+            RPCMessageData::ClientConnected(message) => Some(ClientEvent::Connected(message)),
+            RPCMessageData::ClientDisconnected(message) => Some(ClientEvent::Disconnected(message)),
+            _ => None,
+        }
+    }
+}
+
+impl From<ClientEvent> for RPCMessageData {
+    fn from(value: ClientEvent) -> Self {
+        match value {
+            ClientEvent::Connected(message) => RPCMessageData::ClientConnected(message),
+            ClientEvent::Disconnected(message) => RPCMessageData::ClientDisconnected(message),
+        }
+    }
+}
+
+/// Narrows down client events. Empty lists match everything.
+#[derive(Default, Clone, Debug)]
+pub struct ClientEventFilter {
+    /// Keep events that touch any of these channel names
+    pub channels: Vec<String>,
+
+    /// Keep events from any of these users
+    pub user_ids: Vec<String>,
+}
+
+impl ClientEventFilter {
+    pub fn matches(&self, event: &ClientEvent) -> bool {
+        let channel_match = self.channels.is_empty()
+            || event
+                .channels()
+                .iter()
+                .any(|(channel, _, _)| self.channels.contains(&channel.name));
+
+        let user_match = self.user_ids.is_empty()
+            || event
+                .user_id()
+                .is_some_and(|user_id| self.user_ids.iter().any(|id| id == user_id));
+
+        channel_match && user_match
+    }
+}
+
+/// Publishes a message on the event bus of `namespace`
+pub async fn publish_event_async(
+    client: &Client,
+    namespace: &str,
+    message: impl Into<RPCMessage>,
+) -> anyhow::Result<usize> {
+    let message: RPCMessage = message.into();
+    let topic = topics::events_pubsub_topic(namespace);
+
+    trace!("publishing {message} event to {topic}");
+
+    let raw = message
+        .ser_to_string()
+        .context("failed to serialize to string")?;
+
+    let receivers: usize = client
+        .publish(topic, raw)
+        .await
+        .context("failed to publish event")?;
+
+    Ok(receivers)
+}
+
+pub async fn publish_client_event_async(
+    client: &Client,
+    namespace: &str,
+    event: ClientEvent,
+) -> anyhow::Result<usize> {
+    publish_event_async(client, namespace, RPCMessageData::from(event)).await
+}
+
+/// Subscribes to every message on the event bus of `namespace` over a dedicated connection.
+/// Payloads that fail to parse are logged and skipped.
+pub async fn subscribe_events_async(
+    config: RedisSettings,
+    namespace: &str,
+) -> anyhow::Result<BoxStream<'static, RPCMessage>> {
+    let client = connect_and_ping_async(config)
+        .await
+        .context("failed to connect and ping async to redis")?;
+
+    let topic = topics::events_pubsub_topic(namespace);
+
+    let stream = client
+        .subscribe(topic.clone())
+        .await
+        .context("failed to subscribe to event bus")?;
+
+    debug!("subscribed to event bus {topic}");
+
+    let stream = stream.filter_map(|message| async move {
+        let message = message
+            .inspect_err(|err| warn!("failed to receive event: {err}"))
+            .ok()?;
+
+        RPCMessage::der_from_string(String::from_utf8_lossy(&message.payload).as_ref())
+            .inspect_err(|err| warn!("failed to parse event: {err:#}"))
+            .ok()
+    });
+
+    Ok(stream.boxed())
+}
+
+/// Subscribes to client connection events that pass `filter`
+pub async fn subscribe_client_events_async(
+    config: RedisSettings,
+    namespace: &str,
+    filter: ClientEventFilter,
+) -> anyhow::Result<BoxStream<'static, ClientEvent>> {
+    let stream = subscribe_events_async(config, namespace).await?;
+
+    let stream = stream.filter_map(move |message| {
+        let event = ClientEvent::from_rpc(message.data).filter(|event| filter.matches(event));
+        async move { event }
+    });
+
+    Ok(stream.boxed())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::events::{ClientEvent, ClientEventFilter};
+    use crate::pubsub::{ClientConnectedMessage, ClientDisconnectedMessage, RPCMessageData};
+    use rhiaqey_sdk_rs::channel::Channel;
+
+    fn connected(user_id: Option<&str>, channels: &[&str]) -> ClientEvent {
+        ClientEvent::Connected(ClientConnectedMessage {
+            client_id: "client_1".to_string(),
+            user_id: user_id.map(String::from),
+            channels: channels
+                .iter()
+                .map(|name| {
+                    (
+                        Channel {
+                            name: name.to_string(),
+                            size: 10,
+                        },
+                        None,
+                        None,
+                    )
+                })
+                .collect(),
+        })
+    }
+
+    #[test]
+    fn empty_filter_matches_everything() {
+        let filter = ClientEventFilter::default();
+        assert!(filter.matches(&connected(None, &[])));
+        assert!(filter.matches(&connected(Some("user_1"), &["a"])));
+    }
+
+    #[test]
+    fn filter_by_channel() {
+        let filter = ClientEventFilter {
+            channels: vec!["b".to_string()],
+            ..Default::default()
+        };
+        assert!(filter.matches(&connected(None, &["a", "b"])));
+        assert!(!filter.matches(&connected(None, &["a"])));
+    }
+
+    #[test]
+    fn filter_by_user_and_channel() {
+        let filter = ClientEventFilter {
+            channels: vec!["a".to_string()],
+            user_ids: vec!["user_1".to_string()],
+        };
+        assert!(filter.matches(&connected(Some("user_1"), &["a"])));
+        assert!(!filter.matches(&connected(Some("user_2"), &["a"])));
+        assert!(!filter.matches(&connected(None, &["a"])));
+        assert!(!filter.matches(&connected(Some("user_1"), &["b"])));
+    }
+
+    #[test]
+    fn only_client_events_convert_from_rpc() {
+        let event = ClientEvent::from_rpc(RPCMessageData::ClientDisconnected(
+            ClientDisconnectedMessage {
+                client_id: "client_2".to_string(),
+                user_id: Some("user_2".to_string()),
+                channels: vec![],
+            },
+        ))
+        .unwrap();
+        assert!(!event.is_connected());
+        assert_eq!(event.client_id(), "client_2");
+        assert_eq!(event.user_id(), Some("user_2"));
+
+        assert!(ClientEvent::from_rpc(RPCMessageData::PurgeChannels(vec![])).is_none());
+    }
+}
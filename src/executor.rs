@@ -1,36 +1,312 @@
+use crate::channels::{ChannelChange, diff_channels};
+use crate::compression::{CompressionOptions, is_compressed};
 use crate::env::Env;
-use crate::pubsub::RPCMessage;
+use crate::events::{ClientEvent, ClientEventFilter};
+use crate::presence::PresenceTracker;
+use crate::pubsub::{
+    PublisherDeregistrationMessage, PublisherHeartbeatMessage, PublisherRegistrationMessage,
+    RPCMessage, RPCMessageData, RPCReply, RPCSender, RPCTarget,
+};
 use crate::redis::{RhiaqeyBufVec, connect_and_ping_async};
 use crate::redis_rs::connect_and_ping;
 use crate::security::SecurityKey;
+use crate::settings::{SettingsState, SettingsWatcher, validate_settings};
 use crate::stream::StreamMessage;
-use crate::{security, topics};
+use crate::{clock, events, registry, rpc, scripts, security, snapshot, topics};
 use anyhow::{Context, bail};
-use log::{debug, info, trace};
+use futures_util::StreamExt;
+use futures_util::stream::BoxStream;
+use log::{debug, info, trace, warn};
 use redis::Commands;
 use rhiaqey_sdk_rs::channel::{Channel, ChannelList};
 use rhiaqey_sdk_rs::message::MessageValue;
-use rustis::client::{Client, PubSubMessage, PubSubStream};
+use rustis::client::{BatchPreparedCommand, Client, PubSubMessage, PubSubStream};
 use rustis::commands::{
-    PubSubCommands, StreamCommands, StringCommands, XAddOptions, XTrimOperator, XTrimOptions,
+    CallBuilder, PubSubCommands, ScriptingCommands, StreamCommands, StringCommands, XAddOptions,
+    XTrimOperator, XTrimOptions,
 };
+use rustis::resp::BulkString;
+use rusty_ulid::generate_ulid_string;
 use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
-use std::fmt::Debug;
+use std::fmt::{Debug, Display, Formatter};
 use std::sync::Arc;
-use tokio::sync::{Mutex, RwLock};
+use std::time::{Duration, Instant};
+use tokio::sync::{Mutex, OnceCell, RwLock, broadcast, watch};
+use tokio::task::JoinHandle;
 
+#[derive(Clone)]
 pub struct Executor {
     env: Arc<Env>,
     redis: Arc<Mutex<Client>>,
     redis_rs: Arc<std::sync::Mutex<redis::Connection>>,
     channels: Arc<RwLock<Vec<Channel>>>,
+    channel_changes: broadcast::Sender<ChannelChange>,
     security: Arc<Mutex<SecurityKey>>,
+    started: Instant,
+    /// Sha1 of `scripts::GUARDED_XADD` once loaded
+    guarded_xadd_sha1: Arc<OnceCell<String>>,
+    /// Task started by `start_heartbeat`, aborted by `deregister_async`
+    heartbeat: Arc<std::sync::Mutex<Option<JoinHandle<()>>>>,
+}
+
+/// How long `register` waits for a hub to acknowledge each attempt
+const REGISTER_ACK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Upper bound for the delay between registration attempts
+const REGISTER_MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// How long idempotency keys are remembered unless set in the publish options
+pub const DEFAULT_IDEMPOTENCY_TTL: Duration = Duration::from_secs(600);
+
+/// How long accepted timestamps are kept unless set in the publish options
+pub const DEFAULT_STALE_WRITE_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// How a channel's raw stream is trimmed when publishing to it
+#[derive(Default, Clone, Debug, PartialEq)]
+pub enum TrimPolicy {
+    /// Keep as many entries as the channel's configured size. Channels without a size
+    /// are not trimmed.
+    #[default]
+    ChannelSize,
+    /// Keep at most this many entries on every channel
+    MaxLen(i64),
+    /// Drop entries older than this at the time of publishing (`MINID`). The cutoff is
+    /// taken from this host's clock, so clock skew between publishers shifts the kept
+    /// window, and a clock running ahead of the others can trim their fresh entries.
+    MaxAge(Duration),
+    /// Never trim
+    None,
+}
+
+/// Resolved `XADD` trim argument
+#[derive(Clone, Debug, PartialEq)]
+pub enum TrimThreshold {
+    MaxLen(i64),
+    MinId(String),
+}
+
+impl TrimPolicy {
+    /// Trim threshold for a channel of `channel_size` at `now` milliseconds since epoch
+    pub fn threshold(&self, channel_size: usize, now: u64) -> Option<TrimThreshold> {
+        match self {
+            TrimPolicy::ChannelSize if channel_size == 0 => None,
+            TrimPolicy::ChannelSize => Some(TrimThreshold::MaxLen(channel_size as i64)),
+            TrimPolicy::MaxLen(max_len) => Some(TrimThreshold::MaxLen(*max_len)),
+            TrimPolicy::MaxAge(age) => {
+                let min = now.saturating_sub(age.as_millis() as u64);
+                Some(TrimThreshold::MinId(format!("{min}-0")))
+            }
+            TrimPolicy::None => None,
+        }
+    }
+}
+
+/// Which of the assigned channels a message is published to
+#[derive(Default, Clone)]
+pub enum ChannelTarget {
+    #[default]
+    All,
+    /// Only these channels. Each one must be assigned, repeated names are published to once.
+    Names(Vec<String>),
+    /// Only the assigned channels this returns `true` for
+    Filter(Arc<dyn Fn(&Channel) -> bool + Send + Sync>),
+}
+
+impl Debug for ChannelTarget {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ChannelTarget::All => write!(f, "All"),
+            ChannelTarget::Names(names) => f.debug_tuple("Names").field(names).finish(),
+            ChannelTarget::Filter(_) => write!(f, "Filter(..)"),
+        }
+    }
+}
+
+impl ChannelTarget {
+    pub fn names<S: Into<String>>(names: impl IntoIterator<Item = S>) -> Self {
+        ChannelTarget::Names(names.into_iter().map(Into::into).collect())
+    }
+
+    pub fn filter(predicate: impl Fn(&Channel) -> bool + Send + Sync + 'static) -> Self {
+        ChannelTarget::Filter(Arc::new(predicate))
+    }
+
+    /// Picks the targeted channels out of the assigned `channels`
+    pub fn select(&self, channels: &[Channel]) -> anyhow::Result<Vec<Channel>> {
+        match self {
+            ChannelTarget::All => Ok(channels.to_vec()),
+            ChannelTarget::Names(names) => names
+                .iter()
+                .enumerate()
+                .filter(|(i, name)| !names[..*i].contains(name))
+                .map(|(_, name)| {
+                    channels
+                        .iter()
+                        .find(|channel| channel.name.as_str() == name.as_str())
+                        .cloned()
+                        .with_context(|| format!("channel {name} is not assigned"))
+                })
+                .collect(),
+            ChannelTarget::Filter(predicate) => Ok(channels
+                .iter()
+                .filter(|channel| predicate(channel))
+                .cloned()
+                .collect()),
+        }
+    }
 }
 
 #[derive(Default, Clone, Debug)]
 pub struct ExecutorPublishOptions {
+    pub channels: ChannelTarget,
+    /// Keep publishing to the remaining channels after one fails and return the report
+    /// with the failures in it, instead of stopping with a `PublishError`
+    pub continue_on_error: bool,
+    pub trim: TrimPolicy,
+    /// Keep at most this many entries, takes precedence over `trim`
+    #[deprecated(note = "use `trim` with `TrimPolicy::MaxLen` instead")]
     pub trim_threshold: Option<i64>,
+    /// Trim exactly instead of letting redis trim whole nodes (`~`), which is cheaper
+    pub trim_exact: bool,
+    pub compression: CompressionOptions,
+    /// Overrides the message's own idempotency key
+    pub idempotency_key: Option<String>,
+    /// How long an idempotency key is remembered, `DEFAULT_IDEMPOTENCY_TTL` if not set.
+    /// Must be at least a millisecond.
+    pub idempotency_ttl: Option<Duration>,
+    /// Drop messages whose timestamp is older than the last one accepted for the same
+    /// channel, category and key. Messages without a timestamp are always stored.
+    pub reject_stale_writes: bool,
+    /// How long the accepted timestamp of a category and key is kept once no newer message
+    /// arrives for it, `DEFAULT_STALE_WRITE_TTL` if not set. Afterwards any timestamp is
+    /// accepted. Must be at least a millisecond.
+    pub stale_write_ttl: Option<Duration>,
+    /// Also store the message as the snapshot of its key, atomically with adding it
+    pub update_snapshot: bool,
+}
+
+/// What happened when publishing to a single channel
+#[derive(Clone, Debug, PartialEq)]
+pub enum PublishOutcome {
+    /// Added to the stream under this id
+    Sent(String),
+    /// Skipped because the idempotency key was already used, by the entry with this id
+    Duplicate(String),
+    /// Dropped because a message with this newer timestamp was already accepted
+    Stale(u64),
+    Failed(String),
+    /// Not attempted because an earlier channel failed
+    Skipped,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ChannelPublishReport {
+    pub channel: String,
+    pub topic: String,
+    pub outcome: PublishOutcome,
+}
+
+/// Per-channel result of `Executor::publish_async`, in publishing order
+#[must_use]
+#[derive(Default, Clone, Debug, PartialEq)]
+pub struct PublishReport {
+    pub channels: Vec<ChannelPublishReport>,
+}
+
+impl PublishReport {
+    /// Number of channels the message was added to
+    pub fn delivered(&self) -> usize {
+        self.channels
+            .iter()
+            .filter(|report| matches!(report.outcome, PublishOutcome::Sent(_)))
+            .count()
+    }
+
+    pub fn failures(&self) -> impl Iterator<Item = &ChannelPublishReport> {
+        self.channels
+            .iter()
+            .filter(|report| matches!(report.outcome, PublishOutcome::Failed(_)))
+    }
+
+    /// Whether every targeted channel has the message, including earlier deliveries
+    /// of duplicates
+    pub fn is_complete(&self) -> bool {
+        self.channels.iter().all(|report| {
+            matches!(
+                report.outcome,
+                PublishOutcome::Sent(_) | PublishOutcome::Duplicate(_)
+            )
+        })
+    }
+}
+
+/// Returned, wrapped in `anyhow::Error`, when a channel fails and `continue_on_error`
+/// is off. Use `downcast_ref` to see which channels got the message before that.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PublishError {
+    pub report: PublishReport,
+}
+
+impl Display for PublishError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "failed to publish message")?;
+        for (i, failure) in self.report.failures().enumerate() {
+            let separator = if i == 0 { ": " } else { "; " };
+            if let PublishOutcome::Failed(reason) = &failure.outcome {
+                write!(f, "{separator}{}: {reason}", failure.channel)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for PublishError {}
+
+impl ExecutorPublishOptions {
+    /// Rejects ttls that redis can not express in milliseconds, before anything is sent
+    fn validate(&self) -> anyhow::Result<()> {
+        if self.idempotency_ttl.is_some_and(|ttl| ttl.as_millis() == 0) {
+            bail!("idempotency ttl must be at least 1ms");
+        }
+
+        if self.stale_write_ttl.is_some_and(|ttl| ttl.as_millis() == 0) {
+            bail!("stale write ttl must be at least 1ms");
+        }
+
+        Ok(())
+    }
+
+    fn trim_policy(&self) -> TrimPolicy {
+        #[allow(deprecated)]
+        match self.trim_threshold {
+            Some(max_len) => TrimPolicy::MaxLen(max_len),
+            None => self.trim.clone(),
+        }
+    }
+
+    fn resolve_trim(&self, channel_size: usize) -> Option<TrimThreshold> {
+        self.trim_policy()
+            .threshold(channel_size, clock::now_millis())
+    }
+
+    fn xadd_options(&self, channel_size: usize) -> XAddOptions {
+        let operator = if self.trim_exact {
+            XTrimOperator::Equal
+        } else {
+            XTrimOperator::Approximately
+        };
+
+        match self.resolve_trim(channel_size) {
+            Some(TrimThreshold::MaxLen(max_len)) => {
+                XAddOptions::default().trim_options(XTrimOptions::max_len(operator, max_len))
+            }
+            Some(TrimThreshold::MinId(min_id)) => {
+                XAddOptions::default().trim_options(XTrimOptions::min_id(operator, min_id))
+            }
+            None => XAddOptions::default(),
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Default, Clone, Debug)]
@@ -72,8 +348,43 @@ impl Executor {
     }
 
     pub async fn set_channels_async(&mut self, channels: Vec<Channel>) {
-        let mut locked_channels = self.channels.write().await;
-        *locked_channels = channels;
+        self.replace_channels_async(channels).await;
+    }
+
+    /// Swaps the channel list under a single write lock and notifies
+    /// `subscribe_channel_changes` receivers about the difference
+    pub async fn replace_channels_async(&self, channels: Vec<Channel>) -> Vec<ChannelChange> {
+        self.replace_channels_with_async(|_| channels).await
+    }
+
+    /// Replaces the assigned channels with what `update` makes of the current ones,
+    /// holding the write lock throughout so concurrent updates cannot interleave
+    pub async fn replace_channels_with_async(
+        &self,
+        update: impl FnOnce(&[Channel]) -> Vec<Channel>,
+    ) -> Vec<ChannelChange> {
+        let changes = {
+            let mut locked_channels = self.channels.write().await;
+            let channels = update(locked_channels.as_slice());
+            let changes = diff_channels(locked_channels.as_slice(), channels.as_slice());
+            *locked_channels = channels;
+            changes
+        };
+
+        for change in changes.iter() {
+            // no receivers is not an error
+            let _ = self.channel_changes.send(change.clone());
+        }
+
+        changes
+    }
+
+    pub async fn get_channels_async(&self) -> Vec<Channel> {
+        self.channels.read().await.clone()
+    }
+
+    pub fn subscribe_channel_changes(&self) -> broadcast::Receiver<ChannelChange> {
+        self.channel_changes.subscribe()
     }
 
     pub async fn get_channel_count_async(&self) -> usize {
@@ -153,9 +464,7 @@ impl Executor {
         Ok(channels)
     }
 
-    pub async fn read_settings_async<S: DeserializeOwned + Default>(&self) -> anyhow::Result<S> {
-        info!("reading publisher settings");
-
+    async fn read_settings_data_async(&self) -> anyhow::Result<Vec<u8>> {
         let settings_key = topics::publisher_settings_key(self.get_namespace(), self.get_name());
         let result: RhiaqeyBufVec = self
             .redis
@@ -178,6 +487,41 @@ impl Executor {
 
         trace!("settings decrypted");
 
+        Ok(data)
+    }
+
+    pub async fn read_settings_async<S: DeserializeOwned + Default>(&self) -> anyhow::Result<S> {
+        info!("reading publisher settings");
+
+        let data = self.read_settings_data_async().await?;
+
+        let settings = MessageValue::Binary(data)
+            .decode::<S>()
+            .context("failed to decode settings")?;
+
+        debug!("decrypted data decoded into settings");
+
+        Ok(settings)
+    }
+
+    /// Same as `read_settings_async` but validates the decrypted document against the
+    /// schema stored at `publisher_schema_key` first. Validation failures come back as a
+    /// `SettingsValidationError` listing every offending field.
+    pub async fn read_validated_settings_async<S: DeserializeOwned>(&self) -> anyhow::Result<S> {
+        info!("reading and validating publisher settings");
+
+        let data = self.read_settings_data_async().await?;
+
+        match self.read_schema_async().await? {
+            None => warn!("no schema found for publisher, settings will not be validated"),
+            Some(schema) => {
+                let document = serde_json::from_slice::<serde_json::Value>(data.as_slice())
+                    .context("settings are not a valid json document")?;
+                validate_settings(&schema, &document)?;
+                debug!("settings validated against schema");
+            }
+        }
+
         let settings = MessageValue::Binary(data)
             .decode::<S>()
             .context("failed to decode settings")?;
@@ -187,6 +531,179 @@ impl Executor {
         Ok(settings)
     }
 
+    pub async fn read_schema_async(&self) -> anyhow::Result<Option<serde_json::Value>> {
+        let schema_key = topics::publisher_schema_key(self.get_namespace(), self.get_name());
+
+        let result: Option<String> = self
+            .redis
+            .lock()
+            .await
+            .get(schema_key)
+            .await
+            .context("failed to retrieve publisher schema")?;
+
+        let Some(schema) = result else {
+            return Ok(None);
+        };
+
+        let schema = serde_json::from_str::<serde_json::Value>(schema.as_str())
+            .context("failed to deserialize publisher schema")?;
+
+        Ok(Some(schema))
+    }
+
+    /// Stores the publisher's settings schema so that hubs and `read_validated_settings_async`
+    /// can validate against it. Meant to be called once at startup.
+    pub async fn write_schema_async(&self, schema: &serde_json::Value) -> anyhow::Result<()> {
+        info!("writing publisher schema");
+
+        let schema_key = topics::publisher_schema_key(self.get_namespace(), self.get_name());
+        let data = serde_json::to_string(schema).context("failed to serialize schema")?;
+
+        self.redis
+            .lock()
+            .await
+            .set(schema_key, data)
+            .await
+            .context("failed to store publisher schema")?;
+
+        debug!("publisher schema stored");
+
+        Ok(())
+    }
+
+    /// Loads settings and keeps them up to date. Every `UpdatePublisherSettings` rpc
+    /// triggers a re-read through `read_validated_settings_async`; updates that fail to
+    /// validate or decode are rejected and the previous settings stay in place.
+    /// Fails if the initial settings cannot be read or validated.
+    pub async fn watch_settings_async<S>(&self) -> anyhow::Result<SettingsWatcher<S>>
+    where
+        S: DeserializeOwned + Send + Sync + 'static,
+    {
+        let mut executor = self.clone();
+
+        let mut stream = executor
+            .create_hub_to_publishers_pubsub_async()
+            .await
+            .context("failed to subscribe for settings updates")?;
+
+        let settings = executor
+            .read_validated_settings_async::<S>()
+            .await
+            .context("failed to read initial settings")?;
+
+        let (sender, receiver) = watch::channel(SettingsState::new(settings));
+
+        let handle = tokio::spawn(async move {
+            while let Some(message) = stream.next().await {
+                let message = match message {
+                    Ok(message) => message,
+                    Err(err) => {
+                        warn!("failed to receive settings update: {err}");
+                        continue;
+                    }
+                };
+
+                let Some(rpc) = executor.extract_pubsub_message(message) else {
+                    continue;
+                };
+
+                if !matches!(rpc.data, RPCMessageData::UpdatePublisherSettings(_)) {
+                    continue;
+                }
+
+                info!("settings update received");
+
+                match executor.read_validated_settings_async::<S>().await {
+                    Ok(settings) => {
+                        sender.send_modify(|state| state.accept(settings));
+                        debug!("settings updated");
+                    }
+                    Err(err) => {
+                        warn!("settings update rejected, keeping previous settings: {err:#}");
+                        sender.send_modify(|state| state.reject(format!("{err:#}")));
+                    }
+                }
+            }
+
+            debug!("settings watcher stopped");
+        });
+
+        Ok(SettingsWatcher::new(receiver, handle))
+    }
+
+    /// Applies channel related rpc messages to the assigned channel list.
+    /// Returns `None` for messages that do not concern channels.
+    pub async fn handle_channels_rpc_async(
+        &self,
+        data: &RPCMessageData,
+    ) -> anyhow::Result<Option<Vec<ChannelChange>>> {
+        let changes = match data {
+            RPCMessageData::AssignChannels(channels) => {
+                debug!("{} channel(s) assigned", channels.len());
+                self.replace_channels_async(channels.clone()).await
+            }
+            RPCMessageData::CreateChannels(_) => {
+                // assignments live in redis, so re-read them to pick up new and resized channels
+                let channels = self
+                    .read_channels_async()
+                    .await
+                    .context("failed to read channels after creation")?;
+                self.replace_channels_async(channels).await
+            }
+            RPCMessageData::DeleteChannels(deleted) => {
+                self.replace_channels_with_async(|current| {
+                    current
+                        .iter()
+                        .filter(|x| !deleted.iter().any(|y| x.name == y.name))
+                        .cloned()
+                        .collect()
+                })
+                .await
+            }
+            _ => return Ok(None),
+        };
+
+        info!("channels updated with {} change(s)", changes.len());
+
+        Ok(Some(changes))
+    }
+
+    /// Keeps the assigned channels in sync with `AssignChannels`, `CreateChannels`
+    /// and `DeleteChannels` rpc messages. Abort the returned handle to stop.
+    pub async fn watch_channels_async(&self) -> anyhow::Result<JoinHandle<()>> {
+        let mut executor = self.clone();
+
+        let mut stream = executor
+            .create_hub_to_publishers_pubsub_async()
+            .await
+            .context("failed to subscribe for channel updates")?;
+
+        let handle = tokio::spawn(async move {
+            while let Some(message) = stream.next().await {
+                let message = match message {
+                    Ok(message) => message,
+                    Err(err) => {
+                        warn!("failed to receive channel update: {err}");
+                        continue;
+                    }
+                };
+
+                let Some(rpc) = executor.extract_pubsub_message(message) else {
+                    continue;
+                };
+
+                if let Err(err) = executor.handle_channels_rpc_async(&rpc.data).await {
+                    warn!("failed to apply {} rpc: {err:#}", rpc.data);
+                }
+            }
+
+            debug!("channel watcher stopped");
+        });
+
+        Ok(handle)
+    }
+
     pub async fn setup(config: Env) -> anyhow::Result<Executor> {
         let redis_rs_client =
             connect_and_ping(&config.redis).context("failed to connect and ping redis")?;
@@ -202,12 +719,18 @@ impl Executor {
             .await
             .context("failed to connect and ping async to redis")?;
 
+        let (channel_changes, _) = broadcast::channel(64);
+
         let mut executor = Executor {
             env: Arc::from(config),
             channels: Arc::from(RwLock::new(vec![])),
+            channel_changes,
             redis: Arc::new(Mutex::new(client)),
             redis_rs: Arc::new(std::sync::Mutex::new(redis_rs_connection)),
             security: Arc::new(Mutex::new(security)),
+            started: Instant::now(),
+            guarded_xadd_sha1: Arc::new(OnceCell::new()),
+            heartbeat: Arc::new(std::sync::Mutex::new(None)),
         };
 
         let channels = executor
@@ -220,7 +743,15 @@ impl Executor {
     }
 
     pub fn extract_pubsub_message(&mut self, message: PubSubMessage) -> Option<RPCMessage> {
-        serde_json::from_slice::<RPCMessage>(message.payload.as_slice()).ok()
+        let message = serde_json::from_slice::<RPCMessage>(message.payload.as_slice())
+            .inspect_err(|err| warn!("failed to parse rpc message: {err}"))
+            .ok()?;
+
+        if let RPCMessageData::Unknown(unknown) = &message.data {
+            debug!("received unknown rpc message kind {}", unknown.kind);
+        }
+
+        Some(message)
     }
 
     pub async fn create_hub_to_publishers_pubsub_async(&mut self) -> anyhow::Result<PubSubStream> {
@@ -229,25 +760,54 @@ impl Executor {
             .context("failed to connect and ping async to redis")?;
 
         let key = topics::hub_to_publisher_pubsub_topic(self.get_namespace(), self.get_name());
+        let all_key = topics::hub_to_publishers_pubsub_topic(self.get_namespace());
 
         let stream = client
-            .subscribe(key.clone())
+            .subscribe([key, all_key])
             .await
             .context("failed to subscribe to topic")?;
 
         Ok(stream)
     }
 
+    /// Fills in the sender of outgoing messages that do not name one
+    fn stamp_sender(&self, message: RPCMessage) -> RPCMessage {
+        if message.sender.is_some() {
+            return message;
+        }
+
+        message.with_sender(self.get_id(), self.get_name(), "publisher")
+    }
+
+    /// Sends an rpc message to where its kind is meant to go, see
+    /// `RPCMessageData::default_target`. Kinds without a default are an error.
     pub fn rpc(&self, namespace: &String, message: RPCMessage) -> anyhow::Result<usize> {
+        let Some(target) = message.data.default_target() else {
+            bail!("{message} rpc messages have no default target, send them with rpc_to");
+        };
+        self.publish_rpc(namespace, &target, message)
+    }
+
+    /// Sends an rpc message to an explicit target in this executor's namespace
+    pub fn rpc_to(&self, target: &RPCTarget, message: RPCMessage) -> anyhow::Result<usize> {
+        self.publish_rpc(self.get_namespace(), target, message)
+    }
+
+    fn publish_rpc(
+        &self,
+        namespace: &str,
+        target: &RPCTarget,
+        message: RPCMessage,
+    ) -> anyhow::Result<usize> {
+        let message = self.stamp_sender(message);
+
         info!(
-            "broadcasting rpc message[namespace={}, kind={}] to all hubs",
-            namespace,
-            message.to_string()
+            "sending rpc message[namespace={}, kind={}] to {}",
+            namespace, message, target
         );
 
-        let clean_topic = topics::hub_raw_to_hub_clean_pubsub_topic(namespace);
+        let topic = target.topic(namespace);
 
-        // Prepare to broadcast to all hubs that we have clean message
         let raw = message
             .ser_to_string()
             .context("failed to serialize to string")?;
@@ -256,23 +816,355 @@ impl Executor {
             .redis_rs
             .lock()
             .unwrap()
-            .publish(clean_topic.clone(), raw)
+            .publish(topic.clone(), raw)
             .unwrap_or(0);
 
         trace!(
             "message sent to pubsub {} and received {} as reply",
-            clean_topic, reply
+            topic, reply
         );
 
         Ok(reply)
     }
 
+    /// Publishes a message on this namespace's event bus
+    pub async fn publish_event_async(
+        &self,
+        message: impl Into<RPCMessage>,
+    ) -> anyhow::Result<usize> {
+        let message = self.stamp_sender(message.into());
+        events::publish_event_async(&*self.redis.lock().await, self.get_namespace(), message).await
+    }
+
+    /// Streams client connection events from this namespace's event bus
+    pub async fn subscribe_client_events_async(
+        &self,
+        filter: ClientEventFilter,
+    ) -> anyhow::Result<BoxStream<'static, ClientEvent>> {
+        events::subscribe_client_events_async(self.env.redis.clone(), self.get_namespace(), filter)
+            .await
+            .context("failed to subscribe to client events")
+    }
+
+    /// Tracks who is online on which channel from the event bus. Clients that are not
+    /// re-announced within `ttl` are considered gone. Fails if `ttl` is zero.
+    pub async fn track_presence_async(&self, ttl: Duration) -> anyhow::Result<PresenceTracker> {
+        let events = self
+            .subscribe_client_events_async(ClientEventFilter::default())
+            .await?;
+
+        PresenceTracker::start(
+            self.redis.clone(),
+            self.get_namespace().to_string(),
+            events,
+            ttl,
+        )
+    }
+
+    /// Sends an rpc message to where its kind is meant to go like `rpc` and waits for
+    /// replies. Waits for `expected` replies, or for as many as there were subscribers
+    /// when `None`, until `timeout` expires. Whatever was collected by then is returned.
+    pub async fn request_async(
+        &self,
+        namespace: &str,
+        message: RPCMessage,
+        expected: Option<usize>,
+        timeout: Duration,
+    ) -> anyhow::Result<Vec<RPCReply>> {
+        let Some(target) = message.data.default_target() else {
+            bail!(
+                "{message} rpc messages have no default target, request them with request_to_async"
+            );
+        };
+        self.request_to_async(namespace, &target, message, expected, timeout)
+            .await
+    }
+
+    /// Same as `request_async` but sends the message to an explicit target
+    pub async fn request_to_async(
+        &self,
+        namespace: &str,
+        target: &RPCTarget,
+        message: RPCMessage,
+        expected: Option<usize>,
+        timeout: Duration,
+    ) -> anyhow::Result<Vec<RPCReply>> {
+        let mut message = self.stamp_sender(message);
+        let correlation_id = generate_ulid_string();
+        let reply_topic = topics::rpc_reply_pubsub_topic(namespace, correlation_id.as_str());
+
+        message.correlation_id = Some(correlation_id.clone());
+        message.reply_to = Some(reply_topic.clone());
+
+        info!(
+            "requesting rpc message[namespace={}, kind={}, correlation_id={}] from {}",
+            namespace, message, correlation_id, target
+        );
+
+        // subscribe before publishing so that no reply is missed
+        let client = connect_and_ping_async(self.env.redis.clone())
+            .await
+            .context("failed to connect and ping async to redis")?;
+
+        let mut stream = client
+            .subscribe(reply_topic.clone())
+            .await
+            .context("failed to subscribe to reply topic")?;
+
+        let topic = target.topic(namespace);
+
+        let raw = message
+            .ser_to_string()
+            .context("failed to serialize to string")?;
+
+        let receivers: usize = self
+            .redis
+            .lock()
+            .await
+            .publish(topic, raw)
+            .await
+            .context("failed to publish rpc request")?;
+
+        let expected = expected.unwrap_or(receivers);
+        trace!("rpc request reached {receivers} receiver(s), expecting {expected} reply(ies)");
+
+        let mut replies: Vec<RPCReply> = vec![];
+
+        let collect = async {
+            while replies.len() < expected {
+                let Some(reply) = stream.next().await else {
+                    break;
+                };
+
+                let reply = match reply {
+                    Ok(reply) => reply,
+                    Err(err) => {
+                        warn!("failed to receive rpc reply: {err}");
+                        continue;
+                    }
+                };
+
+                match serde_json::from_slice::<RPCReply>(reply.payload.as_slice()) {
+                    Ok(reply) if reply.correlation_id == correlation_id => replies.push(reply),
+                    Ok(_) => trace!("ignoring reply for another request"),
+                    Err(err) => warn!("failed to parse rpc reply: {err}"),
+                }
+            }
+        };
+
+        if tokio::time::timeout(timeout, collect).await.is_err() {
+            warn!("rpc request {correlation_id} timed out waiting for replies");
+        }
+
+        debug!(
+            "received {} of {} reply(ies) for {}",
+            replies.len(),
+            expected,
+            correlation_id
+        );
+
+        Ok(replies)
+    }
+
+    /// Reports the outcome of handling `request` back to its sender.
+    /// Does nothing for messages that do not expect a reply.
+    pub async fn reply_async(
+        &self,
+        request: &RPCMessage,
+        result: &anyhow::Result<()>,
+    ) -> anyhow::Result<()> {
+        let (Some(correlation_id), Some(reply_to)) = (&request.correlation_id, &request.reply_to)
+        else {
+            return Ok(());
+        };
+
+        let responder = RPCSender {
+            id: self.get_id().to_string(),
+            name: self.get_name().to_string(),
+            kind: String::from("publisher"),
+        };
+
+        let reply = RPCReply::new(correlation_id.as_str(), &responder, result);
+
+        rpc::reply_async(&*self.redis.lock().await, reply_to.as_str(), &reply).await
+    }
+
+    /// Stores `schema` and announces this publisher to the hubs. Keeps retrying with
+    /// backoff until a hub acknowledges the registration. A hub that rejects it is an error.
+    pub async fn register(&self, schema: serde_json::Value) -> anyhow::Result<RPCReply> {
+        self.write_schema_async(&schema)
+            .await
+            .context("failed to store publisher schema")?;
+
+        let message = PublisherRegistrationMessage {
+            id: self.get_id().to_string(),
+            name: self.get_name().to_string(),
+            namespace: self.get_namespace().to_string(),
+            schema,
+        };
+
+        let mut backoff = Duration::from_secs(1);
+        let mut attempt = 1;
+
+        loop {
+            info!(
+                "registering publisher {} (attempt {attempt})",
+                self.get_id()
+            );
+
+            let replies = self
+                .request_async(
+                    self.get_namespace(),
+                    RPCMessageData::RegisterPublisher(message.clone()).into(),
+                    Some(1),
+                    REGISTER_ACK_TIMEOUT,
+                )
+                .await;
+
+            match replies {
+                Ok(replies) => {
+                    if let Some(reply) = replies.into_iter().next() {
+                        if let Some(error) = &reply.error {
+                            bail!("hub {} rejected registration: {error}", reply.name);
+                        }
+
+                        info!("publisher registered with hub {}", reply.name);
+                        return Ok(reply);
+                    }
+
+                    warn!("no hub acknowledged registration, retrying in {backoff:?}");
+                }
+                Err(err) => warn!("failed to send registration: {err:#}, retrying in {backoff:?}"),
+            }
+
+            tokio::time::sleep(backoff).await;
+            backoff = (backoff * 2).min(REGISTER_MAX_BACKOFF);
+            attempt += 1;
+        }
+    }
+
+    /// Publishes an rpc message to its default target using the async client
+    async fn rpc_async(&self, message: RPCMessage) -> anyhow::Result<usize> {
+        let Some(target) = message.data.default_target() else {
+            bail!("{message} rpc messages have no default target, send them with rpc_to");
+        };
+
+        let message = self.stamp_sender(message);
+        let topic = target.topic(self.get_namespace());
+
+        let raw = message
+            .ser_to_string()
+            .context("failed to serialize to string")?;
+
+        let receivers: usize = self
+            .redis
+            .lock()
+            .await
+            .publish(topic, raw)
+            .await
+            .context("failed to publish rpc message")?;
+
+        Ok(receivers)
+    }
+
+    pub async fn heartbeat_message_async(&self) -> PublisherHeartbeatMessage {
+        PublisherHeartbeatMessage {
+            id: self.get_id().to_string(),
+            name: self.get_name().to_string(),
+            namespace: self.get_namespace().to_string(),
+            channels: self.get_channel_count_async().await,
+            uptime: self.started.elapsed().as_secs(),
+            timestamp: clock::now_millis(),
+        }
+    }
+
+    /// Sends a heartbeat every `interval` and keeps this instance in the publisher
+    /// registry. The registry entry expires after three missed heartbeats.
+    /// Replaces a running heartbeat, `deregister_async` stops it. Fails if `interval` is zero.
+    pub fn start_heartbeat(&self, interval: Duration) -> anyhow::Result<()> {
+        if interval.is_zero() {
+            bail!("heartbeat interval must not be zero");
+        }
+
+        let executor = self.clone();
+        let ttl = interval * 3;
+
+        let handle = tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+
+            loop {
+                ticker.tick().await;
+
+                let heartbeat = executor.heartbeat_message_async().await;
+
+                if let Err(err) =
+                    registry::record_heartbeat_async(&*executor.redis.lock().await, &heartbeat, ttl)
+                        .await
+                {
+                    warn!("failed to record heartbeat: {err:#}");
+                }
+
+                match executor
+                    .rpc_async(RPCMessageData::PublisherHeartbeat(heartbeat).into())
+                    .await
+                {
+                    Ok(receivers) => trace!("heartbeat reached {receivers} hub(s)"),
+                    Err(err) => warn!("failed to send heartbeat: {err:#}"),
+                }
+            }
+        });
+
+        if let Some(previous) = self.heartbeat.lock().unwrap().replace(handle) {
+            previous.abort();
+        }
+
+        Ok(())
+    }
+
+    fn stop_heartbeat(&self) {
+        if let Some(handle) = self.heartbeat.lock().unwrap().take() {
+            handle.abort();
+        }
+    }
+
+    /// Stops the heartbeat, removes this instance from the publisher registry and tells
+    /// the hubs it is gone
+    pub async fn deregister_async(&self) -> anyhow::Result<()> {
+        info!("deregistering publisher {}", self.get_id());
+
+        self.stop_heartbeat();
+
+        registry::remove_publisher_async(
+            &*self.redis.lock().await,
+            self.get_namespace(),
+            self.get_name(),
+            self.get_id(),
+        )
+        .await
+        .context("failed to remove publisher from registry")?;
+
+        let message = PublisherDeregistrationMessage {
+            id: self.get_id().to_string(),
+            name: self.get_name().to_string(),
+            namespace: self.get_namespace().to_string(),
+        };
+
+        self.rpc_async(RPCMessageData::DeregisterPublisher(message).into())
+            .await
+            .context("failed to send deregistration")?;
+
+        Ok(())
+    }
+
+    /// Fails with a `PublishError` if a channel fails while `continue_on_error` is off
     pub async fn publish_async(
         &self,
         message: impl Into<StreamMessage>,
         options: ExecutorPublishOptions,
-    ) -> anyhow::Result<usize> {
-        info!("publishing message async to all valid channels");
+    ) -> anyhow::Result<PublishReport> {
+        info!("publishing message async to targeted channels");
+
+        options.validate()?;
 
         let mut stream_msg: StreamMessage = message.into();
 
@@ -280,21 +1172,31 @@ impl Executor {
         stream_msg.publisher_id = Some(self.get_id().to_string());
         // }
 
+        if options.idempotency_key.is_some() {
+            stream_msg.idempotency_key = options.idempotency_key.clone();
+        }
+
         let key = stream_msg.key.clone();
-        let tms = stream_msg.timestamp.unwrap_or(0);
-        let tag = stream_msg.tag.clone().unwrap_or(String::from(""));
         let category = stream_msg.category.clone().unwrap_or(String::from(""));
 
-        let redis = self.redis.lock().await;
-        let channels = self.channels.read().await;
+        let channels = options
+            .channels
+            .select(self.channels.read().await.as_slice())
+            .context("failed to select channels")?;
 
         let channel_size = channels.len();
+        let mut report = PublishReport::default();
+
         if channel_size == 0 {
-            return Ok(0);
+            return Ok(report);
         }
 
+        let redis = self.redis.lock().await;
+
         info!("publishing message to {channel_size} channel(s)");
 
+        let mut failed = false;
+
         for channel in channels.iter() {
             stream_msg.channel = channel.name.to_string();
 
@@ -306,41 +1208,519 @@ impl Executor {
                 topics::publishers_to_hub_stream_topic(self.get_namespace(), channel.name.as_str());
 
             info!(
-                "publishing message to channel={}, max_len={}, topic={}, key={}, category={}, timestamp={:?}",
-                channel.name, channel.size, topic, key, category, stream_msg.timestamp,
+                "publishing message to channel={}, size={}, trim={:?}, topic={}, key={}, category={}, timestamp={:?}",
+                channel.name,
+                channel.size,
+                options.trim_policy(),
+                topic,
+                key,
+                category,
+                stream_msg.timestamp,
             );
 
-            let data = stream_msg
-                .ser_to_string()
-                .context("failed to serialize to string")?;
-
-            let xadd_options = XAddOptions::default().trim_options(XTrimOptions::max_len(
-                XTrimOperator::Approximately,
-                // channel.size as i64,
-                options.trim_threshold.unwrap_or(1000),
-            ));
-
-            let id: String = redis
-                .xadd(
-                    topic.clone(),
-                    "*",
-                    [
-                        ("raw", data.clone()),
-                        ("tag", tag.clone()),
-                        ("tms", format!("{}", tms)),
-                    ],
-                    xadd_options,
-                    // XAddOptions::default()
+            if failed && !options.continue_on_error {
+                report.channels.push(ChannelPublishReport {
+                    channel: channel.name.to_string(),
+                    topic,
+                    outcome: PublishOutcome::Skipped,
+                });
+                continue;
+            }
+
+            let xadd_options = options.xadd_options(channel.size);
+
+            let guarded = stream_msg.idempotency_key.is_some()
+                || (options.reject_stale_writes && stream_msg.timestamp.is_some())
+                || options.update_snapshot;
+
+            let result: anyhow::Result<PublishOutcome> = async {
+                if guarded {
+                    return self
+                        .guarded_xadd_async(
+                            &redis,
+                            topic.as_str(),
+                            channel.name.as_str(),
+                            &stream_msg,
+                            &options,
+                            channel.size,
+                        )
+                        .await;
+                }
+
+                let entry = Self::stream_entry(&stream_msg, options.compression)?;
+
+                let id: String = redis
+                    .xadd(topic.clone(), "*", entry, xadd_options)
+                    .await
+                    .context("failed to xadd to message to stream")?;
+
+                Ok(PublishOutcome::Sent(id))
+            }
+            .await;
+
+            let outcome = match result {
+                Ok(PublishOutcome::Duplicate(id)) => {
+                    debug!(
+                        "skipped duplicate message for channel {}, already stored as {}",
+                        channel.name, id
+                    );
+                    PublishOutcome::Duplicate(id)
+                }
+                Ok(PublishOutcome::Stale(latest)) => {
+                    warn!(
+                        "dropped stale message for channel {}, key={}: timestamp {:?} is older than {}",
+                        channel.name, key, stream_msg.timestamp, latest
+                    );
+                    PublishOutcome::Stale(latest)
+                }
+                Ok(outcome) => {
+                    debug!(
+                        "sent message {:?} to channel {} in topic {}",
+                        outcome, channel.name, topic
+                    );
+                    outcome
+                }
+                Err(err) => {
+                    warn!("failed to publish to channel {}: {err:#}", channel.name);
+                    failed = true;
+                    PublishOutcome::Failed(format!("{err:#}"))
+                }
+            };
+
+            report.channels.push(ChannelPublishReport {
+                channel: channel.name.to_string(),
+                topic,
+                outcome,
+            });
+        }
+
+        if failed && !options.continue_on_error {
+            return Err(PublishError { report }.into());
+        }
+
+        Ok(report)
+    }
+
+    /// Stream entry fields for a message: the serialized payload plus the tag,
+    /// timestamp and compression flag hubs read without decoding it
+    fn stream_fields(
+        message: &StreamMessage,
+        compression: CompressionOptions,
+    ) -> anyhow::Result<[(&'static str, Vec<u8>); 4]> {
+        let data = message
+            .ser_to_compressed(compression)
+            .context("failed to serialize to string")?;
+        let compressed = if is_compressed(data.as_slice()) {
+            "1"
+        } else {
+            "0"
+        };
+
+        let tag = message.tag.clone().unwrap_or_default();
+        let tms = message.timestamp.unwrap_or(0);
+
+        Ok([
+            ("raw", data),
+            ("tag", tag.into_bytes()),
+            ("tms", format!("{}", tms).into_bytes()),
+            ("cmp", compressed.as_bytes().to_vec()),
+        ])
+    }
+
+    fn stream_entry(
+        message: &StreamMessage,
+        compression: CompressionOptions,
+    ) -> anyhow::Result<[(&'static str, BulkString); 4]> {
+        let fields = Self::stream_fields(message, compression)?;
+        Ok(fields.map(|(name, value)| (name, BulkString::from(value))))
+    }
+
+    /// Adds a message to a stream atomically with the idempotency and stale write checks
+    /// and the snapshot update that apply to it
+    async fn guarded_xadd_async(
+        &self,
+        redis: &Client,
+        topic: &str,
+        channel: &str,
+        message: &StreamMessage,
+        options: &ExecutorPublishOptions,
+        channel_size: usize,
+    ) -> anyhow::Result<PublishOutcome> {
+        let fields = Self::stream_fields(message, options.compression)?;
+
+        let idempotency = message.idempotency_key.as_ref().map(|key| {
+            (
+                topics::publisher_idempotency_key(self.get_namespace(), channel, key.as_str()),
+                options.idempotency_ttl.unwrap_or(DEFAULT_IDEMPOTENCY_TTL),
+            )
+        });
+
+        let timestamp = message
+            .timestamp
+            .filter(|_| options.reject_stale_writes)
+            .map(|timestamp| {
+                let scope =
+                    scripts::timestamp_scope(message.category.as_deref(), message.key.as_str());
+                (
+                    topics::publisher_timestamp_key(self.get_namespace(), channel, scope.as_str()),
+                    timestamp,
                 )
+            });
+
+        let snapshot = if options.update_snapshot {
+            let (key, index_key) = snapshot::snapshot_keys(self.get_namespace(), message);
+            let raw = message
+                .ser_to_string()
+                .context("failed to serialize snapshot")?;
+            Some((key, index_key, raw))
+        } else {
+            None
+        };
+
+        let call = scripts::GuardedXAdd {
+            stream: topic.to_string(),
+            idempotency,
+            timestamp,
+            timestamp_ttl: options.stale_write_ttl.unwrap_or(DEFAULT_STALE_WRITE_TTL),
+            snapshot,
+            xadd_args: scripts::xadd_args(
+                options.resolve_trim(channel_size),
+                options.trim_exact,
+                &fields,
+            ),
+        };
+
+        let (status, value) = self.eval_guarded_xadd_async(redis, &call).await?;
+
+        match status {
+            scripts::GUARDED_XADD_ADDED => Ok(PublishOutcome::Sent(value)),
+            scripts::GUARDED_XADD_DUPLICATE => Ok(PublishOutcome::Duplicate(value)),
+            scripts::GUARDED_XADD_STALE => Ok(PublishOutcome::Stale(
+                value
+                    .parse()
+                    .context("failed to parse accepted timestamp")?,
+            )),
+            status => bail!("unexpected guarded xadd status {status}"),
+        }
+    }
+
+    /// Runs `GUARDED_XADD` by its sha1, loading it on first use. Redis drops cached
+    /// scripts on restart or failover, so on `NOSCRIPT` the script is sent in full, which
+    /// caches it again.
+    async fn eval_guarded_xadd_async(
+        &self,
+        redis: &Client,
+        call: &scripts::GuardedXAdd,
+    ) -> anyhow::Result<(i64, String)> {
+        let sha1 = self
+            .guarded_xadd_sha1
+            .get_or_try_init(|| async {
+                redis
+                    .script_load(scripts::GUARDED_XADD)
+                    .await
+                    .context("failed to load guarded xadd script")
+            })
+            .await?;
+
+        let args = || call.args().into_iter().map(BulkString::from);
+
+        let result = redis
+            .evalsha(
+                CallBuilder::sha1(sha1.as_str())
+                    .keys(call.keys())
+                    .args(args()),
+            )
+            .await;
+
+        match result {
+            Err(err) if err.to_string().contains("NOSCRIPT") => {
+                debug!("guarded xadd script is not cached, sending it in full");
+                redis
+                    .eval(
+                        CallBuilder::script(scripts::GUARDED_XADD)
+                            .keys(call.keys())
+                            .args(args()),
+                    )
+                    .await
+                    .context("failed to run guarded xadd")
+            }
+            result => result.context("failed to run guarded xadd"),
+        }
+    }
+
+    /// Publishes many messages to the targeted channels in a single pipelined round trip.
+    /// Returns, for every message in order, the `(channel, stream id)` pairs it was added as.
+    /// Idempotency keys, stale write checks and snapshot updates are not applied, use
+    /// `publish_async` for those.
+    pub async fn publish_batch_async(
+        &self,
+        messages: impl IntoIterator<Item = impl Into<StreamMessage>>,
+        options: ExecutorPublishOptions,
+    ) -> anyhow::Result<Vec<Vec<(String, String)>>> {
+        let channels = options
+            .channels
+            .select(self.channels.read().await.as_slice())
+            .context("failed to select channels")?;
+
+        let messages: Vec<StreamMessage> = messages.into_iter().map(Into::into).collect();
+        if channels.is_empty() || messages.is_empty() {
+            return Ok(vec![vec![]; messages.len()]);
+        }
+
+        info!(
+            "publishing batch of {} message(s) to {} channel(s)",
+            messages.len(),
+            channels.len()
+        );
+
+        // serialize everything before taking the connection
+        let mut entries = Vec::with_capacity(messages.len() * channels.len());
+        for message in messages.iter() {
+            for channel in channels.iter() {
+                let mut stream_msg = message.clone();
+                stream_msg.publisher_id = Some(self.get_id().to_string());
+                stream_msg.channel = channel.name.to_string();
+                stream_msg.size.get_or_insert(channel.size);
+
+                let topic = topics::publishers_to_hub_stream_topic(
+                    self.get_namespace(),
+                    channel.name.as_str(),
+                );
+
+                entries.push((
+                    topic,
+                    Self::stream_entry(&stream_msg, options.compression)?,
+                    options.xadd_options(channel.size),
+                ));
+            }
+        }
+
+        let ids: Vec<String> = {
+            let redis = self.redis.lock().await;
+            let mut pipeline = redis.create_pipeline();
+
+            for (topic, fields, xadd_options) in entries {
+                pipeline.xadd(topic, "*", fields, xadd_options).queue();
+            }
+
+            pipeline
+                .execute()
                 .await
-                .context("failed to xadd to message to stream")?;
+                .context("failed to xadd batch to streams")?
+        };
 
-            debug!(
-                "sent message {} to channel {} in topic {}",
-                id, channel.name, topic
-            );
+        debug!("sent batch as {} stream entries", ids.len());
+
+        let names: Vec<String> = channels
+            .iter()
+            .map(|channel| channel.name.to_string())
+            .collect();
+
+        Ok(ids
+            .chunks(names.len())
+            .map(|ids| names.iter().cloned().zip(ids.iter().cloned()).collect())
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::executor::{
+        ChannelPublishReport, ChannelTarget, ExecutorPublishOptions, PublishError, PublishOutcome,
+        PublishReport, TrimPolicy, TrimThreshold,
+    };
+    use rhiaqey_sdk_rs::channel::Channel;
+    use std::time::Duration;
+
+    fn channels() -> Vec<Channel> {
+        ["a", "b", "c"]
+            .iter()
+            .map(|name| Channel {
+                name: name.to_string(),
+                size: 10,
+            })
+            .collect()
+    }
+
+    fn names(channels: Vec<Channel>) -> Vec<String> {
+        channels
+            .into_iter()
+            .map(|channel| channel.name.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn all_channels_are_targeted_by_default() {
+        let selected = ChannelTarget::default().select(&channels()).unwrap();
+        assert_eq!(names(selected), vec!["a", "b", "c"]);
+    }
+
+    #[test]
+    fn target_channels_by_name() {
+        let selected = ChannelTarget::names(["c", "a"])
+            .select(&channels())
+            .unwrap();
+        assert_eq!(names(selected), vec!["c", "a"]);
+    }
+
+    #[test]
+    fn repeated_channel_names_are_targeted_once() {
+        let selected = ChannelTarget::names(["b", "a", "b"])
+            .select(&channels())
+            .unwrap();
+        assert_eq!(names(selected), vec!["b", "a"]);
+    }
+
+    #[test]
+    fn unknown_channel_names_are_an_error() {
+        let err = ChannelTarget::names(["a", "z"])
+            .select(&channels())
+            .unwrap_err();
+        assert_eq!(err.to_string(), "channel z is not assigned");
+    }
+
+    #[test]
+    fn target_channels_by_predicate() {
+        let selected = ChannelTarget::filter(|channel| channel.name.as_str() != "b")
+            .select(&channels())
+            .unwrap();
+        assert_eq!(names(selected), vec!["a", "c"]);
+    }
+
+    #[test]
+    fn channel_size_is_the_default_trim() {
+        assert_eq!(TrimPolicy::default(), TrimPolicy::ChannelSize);
+        assert_eq!(
+            TrimPolicy::ChannelSize.threshold(50, 0),
+            Some(TrimThreshold::MaxLen(50))
+        );
+    }
+
+    #[test]
+    fn channels_without_size_are_not_trimmed() {
+        assert_eq!(TrimPolicy::ChannelSize.threshold(0, 0), None);
+    }
+
+    #[test]
+    fn deprecated_trim_threshold_overrides_the_policy() {
+        #[allow(deprecated)]
+        let options = ExecutorPublishOptions {
+            trim: TrimPolicy::None,
+            trim_threshold: Some(1000),
+            ..Default::default()
+        };
+        assert_eq!(options.trim_policy(), TrimPolicy::MaxLen(1000));
+        assert_eq!(
+            ExecutorPublishOptions::default().trim_policy(),
+            TrimPolicy::ChannelSize
+        );
+    }
+
+    #[test]
+    fn sub_millisecond_ttls_are_rejected() {
+        assert!(ExecutorPublishOptions::default().validate().is_ok());
+
+        let options = ExecutorPublishOptions {
+            idempotency_ttl: Some(Duration::from_micros(500)),
+            ..Default::default()
+        };
+        assert!(options.validate().is_err());
+
+        let options = ExecutorPublishOptions {
+            stale_write_ttl: Some(Duration::ZERO),
+            ..Default::default()
+        };
+        assert!(options.validate().is_err());
+
+        let options = ExecutorPublishOptions {
+            idempotency_ttl: Some(Duration::from_millis(1)),
+            stale_write_ttl: Some(Duration::from_millis(1)),
+            ..Default::default()
+        };
+        assert!(options.validate().is_ok());
+    }
+
+    #[test]
+    fn max_len_ignores_channel_size() {
+        assert_eq!(
+            TrimPolicy::MaxLen(1000).threshold(50, 0),
+            Some(TrimThreshold::MaxLen(1000))
+        );
+    }
+
+    #[test]
+    fn max_age_trims_by_min_id() {
+        assert_eq!(
+            TrimPolicy::MaxAge(Duration::from_secs(60)).threshold(50, 100_000),
+            Some(TrimThreshold::MinId("40000-0".to_string()))
+        );
+        assert_eq!(
+            TrimPolicy::MaxAge(Duration::from_secs(60)).threshold(50, 1_000),
+            Some(TrimThreshold::MinId("0-0".to_string()))
+        );
+    }
+
+    #[test]
+    fn none_does_not_trim() {
+        assert_eq!(TrimPolicy::None.threshold(50, 0), None);
+    }
+
+    fn partial_report() -> PublishReport {
+        PublishReport {
+            channels: vec![
+                ChannelPublishReport {
+                    channel: "a".to_string(),
+                    topic: "ns:hub:channels:a:raw".to_string(),
+                    outcome: PublishOutcome::Sent("1-0".to_string()),
+                },
+                ChannelPublishReport {
+                    channel: "b".to_string(),
+                    topic: "ns:hub:channels:b:raw".to_string(),
+                    outcome: PublishOutcome::Failed("boom".to_string()),
+                },
+                ChannelPublishReport {
+                    channel: "c".to_string(),
+                    topic: "ns:hub:channels:c:raw".to_string(),
+                    outcome: PublishOutcome::Skipped,
+                },
+            ],
         }
+    }
+
+    #[test]
+    fn report_counts_deliveries() {
+        let report = partial_report();
+
+        assert_eq!(report.delivered(), 1);
+        assert!(!report.is_complete());
+
+        let failures: Vec<&str> = report
+            .failures()
+            .map(|report| report.channel.as_str())
+            .collect();
+        assert_eq!(failures, vec!["b"]);
+
+        let duplicate = PublishReport {
+            channels: vec![ChannelPublishReport {
+                channel: "a".to_string(),
+                topic: "ns:hub:channels:a:raw".to_string(),
+                outcome: PublishOutcome::Duplicate("1-0".to_string()),
+            }],
+        };
+        assert_eq!(duplicate.delivered(), 0);
+        assert!(duplicate.is_complete());
+
+        assert!(PublishReport::default().is_complete());
+    }
+
+    #[test]
+    fn publish_error_carries_the_report() {
+        let err = anyhow::Error::from(PublishError {
+            report: partial_report(),
+        });
+        assert_eq!(err.to_string(), "failed to publish message: b: boom");
 
-        Ok(channel_size)
+        let err = err.downcast_ref::<PublishError>().unwrap();
+        assert_eq!(err.report, partial_report());
     }
 }
@@ -1,9 +1,21 @@
+pub mod channels;
 pub mod client;
+pub mod clock;
+pub mod compression;
+pub mod consumer;
 pub mod env;
+pub mod events;
 pub mod executor;
+pub mod presence;
 pub mod pubsub;
 pub mod redis;
 pub mod redis_rs;
+pub mod registry;
+pub mod rpc;
+pub mod schema;
+pub mod scripts;
 pub mod security;
+pub mod settings;
+pub mod snapshot;
 pub mod stream;
 pub mod topics;
@@ -0,0 +1,471 @@
+use crate::clock::now_millis;
+use crate::events::ClientEvent;
+use crate::{scripts, topics};
+use anyhow::{Context, bail};
+use futures_util::StreamExt;
+use futures_util::stream::BoxStream;
+use log::{debug, trace, warn};
+use rustis::client::Client;
+use rustis::commands::{
+    CallBuilder, ExpireOption, GenericCommands, ScriptingCommands, SetCommands, SortedSetCommands,
+    ZAddOptions, ZRangeOptions, ZRangeSortBy,
+};
+use std::collections::BTreeSet;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{Mutex, broadcast};
+use tokio::task::JoinHandle;
+
+/// A client joining or leaving a channel
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PresenceChange {
+    Joined {
+        channel: String,
+        client_id: String,
+        user_id: Option<String>,
+    },
+    Left {
+        channel: String,
+        client_id: String,
+        user_id: Option<String>,
+    },
+}
+
+impl PresenceChange {
+    pub fn channel(&self) -> &str {
+        match self {
+            PresenceChange::Joined { channel, .. } | PresenceChange::Left { channel, .. } => {
+                channel.as_str()
+            }
+        }
+    }
+
+    pub fn client_id(&self) -> &str {
+        match self {
+            PresenceChange::Joined { client_id, .. } | PresenceChange::Left { client_id, .. } => {
+                client_id.as_str()
+            }
+        }
+    }
+}
+
+/// Score of a presence entry: when it expires, in milliseconds since epoch
+fn expiry_score(now: u64, ttl: Duration) -> f64 {
+    now.saturating_add(ttl.as_millis() as u64) as f64
+}
+
+/// Score range of entries still present at `now`
+fn live_range(now: u64) -> (String, String) {
+    (now.to_string(), String::from("+inf"))
+}
+
+/// Score range of entries expired by `now`, the complement of `live_range`
+fn expired_range(now: u64) -> (String, String) {
+    (String::from("-inf"), format!("({now}"))
+}
+
+/// Channel presence member, a json pair of client and user id so that pruning an
+/// expired entry still knows its user
+fn presence_member(client_id: &str, user_id: Option<&str>) -> String {
+    serde_json::json!([client_id, user_id]).to_string()
+}
+
+fn parse_presence_member(member: &str) -> Option<(String, Option<String>)> {
+    serde_json::from_str(member)
+        .inspect_err(|err| warn!("failed to parse presence member {member}: {err}"))
+        .ok()
+}
+
+/// Sorted, de-duplicated user ids out of channel presence members
+fn unique_users(members: &[String]) -> Vec<String> {
+    members
+        .iter()
+        .filter_map(|member| parse_presence_member(member.as_str()))
+        .filter_map(|(_, user_id)| user_id)
+        .collect::<BTreeSet<_>>()
+        .into_iter()
+        .collect()
+}
+
+/// Drops `channel` from the presence index once nobody is left on it.
+/// A client joining in between is re-indexed by its next announcement.
+async fn unindex_if_empty_async(
+    client: &Client,
+    namespace: &str,
+    channel: &str,
+) -> anyhow::Result<()> {
+    let count: usize = client
+        .zcard(topics::presence_channel_key(namespace, channel))
+        .await
+        .context("failed to count channel presence")?;
+
+    if count == 0 {
+        let _: usize = client
+            .srem(topics::presence_channels_key(namespace), channel)
+            .await
+            .context("failed to unindex presence channel")?;
+    }
+
+    Ok(())
+}
+
+/// Applies a connection event to the presence sets and returns what changed.
+/// Connected events also refresh existing entries, so hubs re-announce their clients
+/// within `ttl` to keep them online. Entries of a crashed hub expire after `ttl`.
+pub async fn record_client_event_async(
+    client: &Client,
+    namespace: &str,
+    event: &ClientEvent,
+    ttl: Duration,
+) -> anyhow::Result<Vec<PresenceChange>> {
+    let client_id = event.client_id();
+    let user_id = event.user_id().map(String::from);
+    let member = presence_member(client_id, user_id.as_deref());
+    let mut changes = vec![];
+
+    if event.is_connected() {
+        let expiry = expiry_score(now_millis(), ttl);
+        let seconds = ttl.as_secs().max(1);
+
+        if let Some(user_id) = &user_id {
+            let user_key = topics::presence_user_key(namespace, user_id.as_str());
+            let _: usize = client
+                .zadd(
+                    user_key.clone(),
+                    [(expiry, client_id)],
+                    ZAddOptions::default(),
+                )
+                .await
+                .context("failed to mark user online")?;
+            let _: bool = client
+                .expire(user_key, seconds, ExpireOption::None)
+                .await
+                .context("failed to set user presence expiry")?;
+        }
+
+        for (channel, _, _) in event.channels() {
+            let channel_key = topics::presence_channel_key(namespace, channel.name.as_str());
+
+            let added: usize = client
+                .zadd(
+                    channel_key.clone(),
+                    [(expiry, member.as_str())],
+                    ZAddOptions::default(),
+                )
+                .await
+                .context("failed to add client to channel presence")?;
+            let _: bool = client
+                .expire(channel_key, seconds, ExpireOption::None)
+                .await
+                .context("failed to set channel presence expiry")?;
+
+            if added > 0 {
+                changes.push(PresenceChange::Joined {
+                    channel: channel.name.to_string(),
+                    client_id: client_id.to_string(),
+                    user_id: user_id.clone(),
+                });
+            }
+        }
+
+        if !event.channels().is_empty() {
+            let channels: Vec<String> = event
+                .channels()
+                .iter()
+                .map(|(channel, _, _)| channel.name.to_string())
+                .collect();
+
+            let _: usize = client
+                .sadd(topics::presence_channels_key(namespace), channels)
+                .await
+                .context("failed to index presence channels")?;
+        }
+    } else {
+        for (channel, _, _) in event.channels() {
+            let removed: usize = client
+                .zrem(
+                    topics::presence_channel_key(namespace, channel.name.as_str()),
+                    member.as_str(),
+                )
+                .await
+                .context("failed to remove client from channel presence")?;
+
+            if removed > 0 {
+                changes.push(PresenceChange::Left {
+                    channel: channel.name.to_string(),
+                    client_id: client_id.to_string(),
+                    user_id: user_id.clone(),
+                });
+            }
+
+            unindex_if_empty_async(client, namespace, channel.name.as_str()).await?;
+        }
+
+        if let Some(user_id) = &user_id {
+            let _: usize = client
+                .zrem(
+                    topics::presence_user_key(namespace, user_id.as_str()),
+                    client_id,
+                )
+                .await
+                .context("failed to remove user client")?;
+        }
+    }
+
+    trace!("presence of {client_id} changed {} time(s)", changes.len());
+
+    Ok(changes)
+}
+
+/// `Left` changes for expired members of `channel`
+fn left_changes(channel: &str, members: &[String]) -> Vec<PresenceChange> {
+    members
+        .iter()
+        .filter_map(|member| parse_presence_member(member.as_str()))
+        .map(|(client_id, user_id)| PresenceChange::Left {
+            channel: channel.to_string(),
+            client_id,
+            user_id,
+        })
+        .collect()
+}
+
+/// Removes clients whose presence expired, e.g. because their hub crashed, and
+/// channels nobody is left on
+pub async fn prune_expired_async(
+    client: &Client,
+    namespace: &str,
+) -> anyhow::Result<Vec<PresenceChange>> {
+    let (min, max) = expired_range(now_millis());
+
+    let channels: Vec<String> = client
+        .smembers(topics::presence_channels_key(namespace))
+        .await
+        .context("failed to read presence channels")?;
+
+    let mut changes = vec![];
+
+    for channel in channels {
+        let channel_key = topics::presence_channel_key(namespace, channel.as_str());
+
+        let expired: Vec<String> = client
+            .eval(
+                CallBuilder::script(scripts::PRUNE_EXPIRED)
+                    .keys([channel_key])
+                    .args([min.clone(), max.clone()]),
+            )
+            .await
+            .context("failed to remove expired clients")?;
+
+        changes.extend(left_changes(channel.as_str(), expired.as_slice()));
+
+        unindex_if_empty_async(client, namespace, channel.as_str()).await?;
+    }
+
+    if !changes.is_empty() {
+        debug!("pruned {} expired presence entries", changes.len());
+    }
+
+    Ok(changes)
+}
+
+async fn channel_members_async(
+    client: &Client,
+    namespace: &str,
+    channel: &str,
+) -> anyhow::Result<Vec<String>> {
+    let (min, max) = live_range(now_millis());
+
+    let members: Vec<String> = client
+        .zrange(
+            topics::presence_channel_key(namespace, channel),
+            min,
+            max,
+            ZRangeOptions::default().sort_by(ZRangeSortBy::ByScore),
+        )
+        .await
+        .context("failed to read channel presence")?;
+
+    Ok(members)
+}
+
+/// Ids of the clients currently on `channel`
+pub async fn channel_clients_async(
+    client: &Client,
+    namespace: &str,
+    channel: &str,
+) -> anyhow::Result<Vec<String>> {
+    let members = channel_members_async(client, namespace, channel).await?;
+
+    Ok(members
+        .iter()
+        .filter_map(|member| parse_presence_member(member.as_str()))
+        .map(|(client_id, _)| client_id)
+        .collect())
+}
+
+/// Ids of the users currently on `channel`. Anonymous clients are left out.
+pub async fn channel_users_async(
+    client: &Client,
+    namespace: &str,
+    channel: &str,
+) -> anyhow::Result<Vec<String>> {
+    let members = channel_members_async(client, namespace, channel).await?;
+
+    Ok(unique_users(members.as_slice()))
+}
+
+pub async fn is_user_online_async(
+    client: &Client,
+    namespace: &str,
+    user_id: &str,
+) -> anyhow::Result<bool> {
+    let (min, max) = live_range(now_millis());
+
+    let count: usize = client
+        .zcount(topics::presence_user_key(namespace, user_id), min, max)
+        .await
+        .context("failed to read user presence")?;
+
+    Ok(count > 0)
+}
+
+/// Keeps the presence sets up to date from a stream of client events and broadcasts
+/// join/leave changes. Stops when dropped.
+pub struct PresenceTracker {
+    changes: broadcast::Sender<PresenceChange>,
+    handle: JoinHandle<()>,
+}
+
+impl PresenceTracker {
+    /// Fails if `ttl` is zero
+    pub fn start(
+        client: Arc<Mutex<Client>>,
+        namespace: String,
+        mut events: BoxStream<'static, ClientEvent>,
+        ttl: Duration,
+    ) -> anyhow::Result<Self> {
+        if ttl.is_zero() {
+            bail!("presence ttl must not be zero");
+        }
+
+        let (changes, _) = broadcast::channel(256);
+        let sender = changes.clone();
+
+        let handle = tokio::spawn(async move {
+            let mut prune = tokio::time::interval(ttl);
+
+            loop {
+                let result = tokio::select! {
+                    event = events.next() => {
+                        let Some(event) = event else {
+                            break;
+                        };
+                        record_client_event_async(&*client.lock().await, &namespace, &event, ttl)
+                            .await
+                    }
+                    _ = prune.tick() => prune_expired_async(&*client.lock().await, &namespace).await,
+                };
+
+                match result {
+                    Ok(changes) => {
+                        for change in changes {
+                            // no receivers is fine
+                            let _ = sender.send(change);
+                        }
+                    }
+                    Err(err) => warn!("failed to update presence: {err:#}"),
+                }
+            }
+
+            debug!("presence tracker stopped");
+        });
+
+        Ok(PresenceTracker { changes, handle })
+    }
+
+    /// Receiver for join/leave changes from now on
+    pub fn subscribe(&self) -> broadcast::Receiver<PresenceChange> {
+        self.changes.subscribe()
+    }
+}
+
+impl Drop for PresenceTracker {
+    fn drop(&mut self) {
+        self.handle.abort();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::presence::{
+        PresenceChange, expired_range, expiry_score, left_changes, live_range, presence_member,
+        unique_users,
+    };
+    use std::time::Duration;
+
+    #[test]
+    fn users_are_unique_and_sorted() {
+        let users = unique_users(&[
+            presence_member("client_1", Some("b")),
+            presence_member("client_2", None),
+            presence_member("client_3", Some("a")),
+            presence_member("client_4", Some("b")),
+        ]);
+        assert_eq!(users, vec!["a".to_string(), "b".to_string()]);
+    }
+
+    #[test]
+    fn entries_expire_after_ttl() {
+        assert_eq!(expiry_score(1_000, Duration::from_secs(30)), 31_000.0);
+        assert_eq!(
+            expiry_score(u64::MAX, Duration::from_secs(30)),
+            u64::MAX as f64
+        );
+    }
+
+    #[test]
+    fn live_and_expired_ranges_meet_at_now() {
+        assert_eq!(live_range(5_000), ("5000".to_string(), "+inf".to_string()));
+        // an entry scored exactly now is still live, so it must not be pruned
+        assert_eq!(
+            expired_range(5_000),
+            ("-inf".to_string(), "(5000".to_string())
+        );
+    }
+
+    #[test]
+    fn pruned_members_keep_their_user() {
+        let members = vec![
+            presence_member("client_1", Some("user_1")),
+            presence_member("client_2", None),
+            "not a member".to_string(),
+        ];
+        assert_eq!(
+            left_changes("channel_1", members.as_slice()),
+            vec![
+                PresenceChange::Left {
+                    channel: "channel_1".to_string(),
+                    client_id: "client_1".to_string(),
+                    user_id: Some("user_1".to_string()),
+                },
+                PresenceChange::Left {
+                    channel: "channel_1".to_string(),
+                    client_id: "client_2".to_string(),
+                    user_id: None,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn change_accessors() {
+        let change = PresenceChange::Left {
+            channel: "channel_1".to_string(),
+            client_id: "client_1".to_string(),
+            user_id: None,
+        };
+        assert_eq!(change.channel(), "channel_1");
+        assert_eq!(change.client_id(), "client_1");
+    }
+}
@@ -1,10 +1,37 @@
 use crate::stream::StreamMessage;
+use crate::{clock, topics};
 use anyhow::Context;
 use rhiaqey_sdk_rs::channel::Channel;
-use serde::{Deserialize, Serialize};
+use schemars::JsonSchema;
+use serde::de::Error;
+use serde::ser::SerializeMap;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use std::fmt::{Debug, Display, Formatter};
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
+/// Envelope version written by this crate. Messages without one are version 1.
+pub const RPC_ENVELOPE_VERSION: u32 = 2;
+
+/// Every `RPCMessageData` kind this crate understands, as it appears on the wire
+pub const RPC_MESSAGE_KINDS: [&str; 12] = [
+    "RegisterPublisher",
+    "DeregisterPublisher",
+    "PublisherHeartbeat",
+    "NotifyClients",
+    "UpdateHubSettings",
+    "UpdatePublisherSettings",
+    "CreateChannels",
+    "DeleteChannels",
+    "PurgeChannels",
+    "AssignChannels",
+    "ClientConnected",
+    "ClientDisconnected",
+];
+
+fn default_envelope_version() -> u32 {
+    1
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
 #[serde(rename_all = "PascalCase")]
 pub struct PublisherRegistrationMessage {
     /// Each pod will have a different id
@@ -20,7 +47,42 @@ pub struct PublisherRegistrationMessage {
     pub schema: serde_json::Value,
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
+#[serde(rename_all = "PascalCase")]
+pub struct PublisherDeregistrationMessage {
+    /// Each pod will have a different id
+    pub id: String,
+
+    /// All deployment pods will have the same name
+    pub name: String,
+
+    /// Namespace of the k8s installation
+    pub namespace: String,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq)]
+#[serde(rename_all = "PascalCase")]
+pub struct PublisherHeartbeatMessage {
+    /// Each pod will have a different id
+    pub id: String,
+
+    /// All deployment pods will have the same name
+    pub name: String,
+
+    /// Namespace of the k8s installation
+    pub namespace: String,
+
+    /// Number of channels currently assigned
+    pub channels: usize,
+
+    /// Seconds since the publisher started
+    pub uptime: u64,
+
+    /// Milliseconds since unix epoch at the time of the heartbeat
+    pub timestamp: u64,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
 #[serde(rename_all = "PascalCase")]
 pub struct ClientConnectedMessage {
     /// Client id
@@ -30,10 +92,11 @@ pub struct ClientConnectedMessage {
     pub user_id: Option<String>,
 
     /// Connected channels
+    #[schemars(with = "Vec<(serde_json::Value, Option<String>, Option<String>)>")]
     pub channels: Vec<(Channel, Option<String>, Option<String>)>,
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
 #[serde(rename_all = "PascalCase")]
 pub struct ClientDisconnectedMessage {
     /// Client id
@@ -43,14 +106,19 @@ pub struct ClientDisconnectedMessage {
     pub user_id: Option<String>,
 
     /// Connected channels
+    #[schemars(with = "Vec<(serde_json::Value, Option<String>, Option<String>)>")]
     pub channels: Vec<(Channel, Option<String>, Option<String>)>,
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
 #[serde(rename_all = "PascalCase")]
 pub enum RPCMessageData {
     // this comes from publishers to hub
     RegisterPublisher(PublisherRegistrationMessage),
+    // this comes from publishers to hub when they shut down
+    DeregisterPublisher(PublisherDeregistrationMessage),
+    // this comes from publishers to hub periodically
+    PublisherHeartbeat(PublisherHeartbeatMessage),
     // this comes from hub raw to hub clean
     NotifyClients(StreamMessage),
     // this goes from hub to hub to notify them all to reload
@@ -58,23 +126,64 @@ pub enum RPCMessageData {
     // this goes from hub to publishers
     UpdatePublisherSettings(Vec<u8>),
     // create channels from http admin
-    CreateChannels(Vec<Channel>),
+    CreateChannels(#[schemars(with = "Vec<serde_json::Value>")] Vec<Channel>),
     // delete channels from http admin
-    DeleteChannels(Vec<Channel>),
+    DeleteChannels(#[schemars(with = "Vec<serde_json::Value>")] Vec<Channel>),
     // empty channel content from http admin
     PurgeChannels(Vec<String>),
     // this goes from hub to all publishers
-    AssignChannels(Vec<Channel>),
+    AssignChannels(#[schemars(with = "Vec<serde_json::Value>")] Vec<Channel>),
     // this goes from hub to eventbus
     ClientConnected(ClientConnectedMessage),
     // this goes from hub to eventbus
     ClientDisconnected(ClientDisconnectedMessage),
+    // sent by a newer peer, kept so that it can be logged or forwarded
+    #[serde(untagged)]
+    Unknown(#[schemars(with = "serde_json::Value")] UnknownRPCMessageData),
+}
+
+/// A message kind this crate does not know about yet
+#[derive(Clone, Debug, PartialEq)]
+pub struct UnknownRPCMessageData {
+    pub kind: String,
+    pub raw: serde_json::Value,
+}
+
+impl Serialize for UnknownRPCMessageData {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if self.raw.is_null() {
+            return serializer.serialize_str(self.kind.as_str());
+        }
+
+        let mut map = serializer.serialize_map(Some(1))?;
+        map.serialize_entry(&self.kind, &self.raw)?;
+        map.end()
+    }
+}
+
+impl<'de> Deserialize<'de> for UnknownRPCMessageData {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let (kind, raw) = match serde_json::Value::deserialize(deserializer)? {
+            serde_json::Value::String(kind) => (kind, serde_json::Value::Null),
+            serde_json::Value::Object(map) if map.len() == 1 => map.into_iter().next().unwrap(),
+            _ => return Err(D::Error::custom("expected a single rpc message kind")),
+        };
+
+        // known kinds that failed to parse are errors, not unknown messages
+        if RPC_MESSAGE_KINDS.contains(&kind.as_str()) {
+            return Err(D::Error::custom(format!("invalid {kind} rpc message")));
+        }
+
+        Ok(UnknownRPCMessageData { kind, raw })
+    }
 }
 
 impl Display for RPCMessageData {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         match self {
             RPCMessageData::RegisterPublisher(_) => write!(f, "register_publisher"),
+            RPCMessageData::DeregisterPublisher(_) => write!(f, "deregister_publisher"),
+            RPCMessageData::PublisherHeartbeat(_) => write!(f, "publisher_heartbeat"),
             RPCMessageData::NotifyClients(_) => write!(f, "notify_clients"),
             RPCMessageData::UpdateHubSettings(_) => write!(f, "update_hub_settings"),
             RPCMessageData::UpdatePublisherSettings(_) => write!(f, "update_publisher_settings"),
@@ -84,14 +193,113 @@ impl Display for RPCMessageData {
             RPCMessageData::AssignChannels(_) => write!(f, "assign_channels"),
             RPCMessageData::ClientConnected(_) => write!(f, "client_connected"),
             RPCMessageData::ClientDisconnected(_) => write!(f, "client_disconnected"),
+            RPCMessageData::Unknown(_) => write!(f, "unknown"),
+        }
+    }
+}
+
+impl RPCMessageData {
+    /// Where this kind of message is meant to go. Channel assignments and publisher settings
+    /// updates are addressed to a single publisher, so they and unknown kinds need an
+    /// explicit target.
+    pub fn default_target(&self) -> Option<RPCTarget> {
+        match self {
+            RPCMessageData::RegisterPublisher(_)
+            | RPCMessageData::DeregisterPublisher(_)
+            | RPCMessageData::PublisherHeartbeat(_)
+            | RPCMessageData::NotifyClients(_)
+            | RPCMessageData::UpdateHubSettings(_)
+            | RPCMessageData::CreateChannels(_)
+            | RPCMessageData::DeleteChannels(_)
+            | RPCMessageData::PurgeChannels(_) => Some(RPCTarget::Hubs),
+            RPCMessageData::ClientConnected(_) | RPCMessageData::ClientDisconnected(_) => {
+                Some(RPCTarget::Events)
+            }
+            RPCMessageData::AssignChannels(_)
+            | RPCMessageData::UpdatePublisherSettings(_)
+            | RPCMessageData::Unknown(_) => None,
+        }
+    }
+}
+
+/// Destination of an rpc message
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RPCTarget {
+    /// All hubs, through the clean topic
+    Hubs,
+    /// Every pod of the publisher with this name
+    Publisher(String),
+    /// Every publisher
+    Publishers,
+    /// The event bus
+    Events,
+}
+
+impl RPCTarget {
+    pub fn topic(&self, namespace: &str) -> String {
+        match self {
+            RPCTarget::Hubs => topics::hub_raw_to_hub_clean_pubsub_topic(namespace),
+            RPCTarget::Publisher(name) => {
+                topics::hub_to_publisher_pubsub_topic(namespace, name.as_str())
+            }
+            RPCTarget::Publishers => topics::hub_to_publishers_pubsub_topic(namespace),
+            RPCTarget::Events => topics::events_pubsub_topic(namespace),
+        }
+    }
+}
+
+impl Display for RPCTarget {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RPCTarget::Hubs => write!(f, "hubs"),
+            RPCTarget::Publisher(name) => write!(f, "publisher {name}"),
+            RPCTarget::Publishers => write!(f, "publishers"),
+            RPCTarget::Events => write!(f, "events"),
         }
     }
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq)]
+#[serde(rename_all = "PascalCase")]
+pub struct RPCSender {
+    /// Each pod will have a different id
+    pub id: String,
+
+    /// All deployment pods will have the same name
+    pub name: String,
+
+    /// Type of process, e.g. hub, publisher or admin
+    pub kind: String,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
 #[serde(rename_all = "PascalCase")]
 pub struct RPCMessage {
+    #[serde(default = "default_envelope_version")]
+    pub version: u32,
+
     pub data: RPCMessageData,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sender: Option<RPCSender>,
+
+    /// Milliseconds since unix epoch at the time the message was created
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timestamp: Option<u64>,
+
+    /// Set when the sender waits for replies
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub correlation_id: Option<String>,
+
+    /// Topic replies should be published to
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reply_to: Option<String>,
+}
+
+impl From<RPCMessageData> for RPCMessage {
+    fn from(value: RPCMessageData) -> Self {
+        RPCMessage::new(value)
+    }
 }
 
 impl Display for RPCMessage {
@@ -101,6 +309,34 @@ impl Display for RPCMessage {
 }
 
 impl RPCMessage {
+    pub fn new(data: RPCMessageData) -> Self {
+        RPCMessage {
+            version: RPC_ENVELOPE_VERSION,
+            data,
+            sender: None,
+            timestamp: Some(clock::now_millis()),
+            correlation_id: None,
+            reply_to: None,
+        }
+    }
+
+    pub fn with_sender(mut self, id: &str, name: &str, kind: &str) -> Self {
+        self.sender = Some(RPCSender {
+            id: id.to_string(),
+            name: name.to_string(),
+            kind: kind.to_string(),
+        });
+        self
+    }
+
+    pub fn is_unknown(&self) -> bool {
+        matches!(self.data, RPCMessageData::Unknown(_))
+    }
+
+    pub fn expects_reply(&self) -> bool {
+        self.correlation_id.is_some() && self.reply_to.is_some()
+    }
+
     pub fn ser_to_string(&self) -> anyhow::Result<String> {
         serde_json::to_string(self).context("failed to serialize")
     }
@@ -110,14 +346,98 @@ impl RPCMessage {
     }
 }
 
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
+#[serde(rename_all = "PascalCase")]
+pub struct RPCReply {
+    /// Correlation id of the request this replies to
+    pub correlation_id: String,
+
+    /// Id of the responding pod
+    pub id: String,
+
+    /// Name of the responding deployment
+    pub name: String,
+
+    /// Set when the request could not be applied
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl RPCReply {
+    /// Reply from `responder` with the outcome of handling the request `correlation_id`
+    pub fn new(correlation_id: &str, responder: &RPCSender, result: &anyhow::Result<()>) -> Self {
+        RPCReply {
+            correlation_id: correlation_id.to_string(),
+            id: responder.id.clone(),
+            name: responder.name.clone(),
+            error: result.as_ref().err().map(|err| format!("{err:#}")),
+        }
+    }
+
+    pub fn is_success(&self) -> bool {
+        self.error.is_none()
+    }
+
+    pub fn ser_to_string(&self) -> anyhow::Result<String> {
+        serde_json::to_string(self).context("failed to serialize")
+    }
+
+    pub fn der_from_string(message: &str) -> anyhow::Result<RPCReply> {
+        serde_json::from_str::<RPCReply>(message).context("failed to deserialize")
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use crate::pubsub::{RPCMessage, RPCMessageData};
+    use crate::pubsub::{
+        ClientConnectedMessage, ClientDisconnectedMessage, PublisherHeartbeatMessage,
+        RPC_ENVELOPE_VERSION, RPCMessage, RPCMessageData, RPCReply, RPCSender, RPCTarget,
+        UnknownRPCMessageData,
+    };
+
+    #[test]
+    fn rpc_message_without_correlation_can_be_deserialized() {
+        let rpc_message =
+            RPCMessage::der_from_string("{\"Data\":{\"PurgeChannels\":[\"a\"]}}").unwrap();
+        assert!(rpc_message.correlation_id.is_none());
+        assert!(!rpc_message.expects_reply());
+    }
+
+    #[test]
+    fn rpc_message_correlation_round_trips() {
+        let mut rpc_message = RPCMessage::new(RPCMessageData::PurgeChannels(vec![]));
+        rpc_message.correlation_id = Some("1".to_string());
+        rpc_message.reply_to = Some("replies".to_string());
+        let serialized = rpc_message.ser_to_string().unwrap();
+        assert!(serialized.contains("\"CorrelationId\":\"1\""));
+        let deserialized = RPCMessage::der_from_string(serialized.as_str()).unwrap();
+        assert!(deserialized.expects_reply());
+        assert_eq!(deserialized.reply_to, Some("replies".to_string()));
+    }
+
+    #[test]
+    fn rpc_reply_carries_the_handler_result() {
+        let responder = RPCSender {
+            id: "1".to_string(),
+            name: "publisher".to_string(),
+            kind: "publisher".to_string(),
+        };
+
+        let reply = RPCReply::new("c", &responder, &Ok(()));
+        assert!(reply.is_success());
+        assert_eq!(reply.correlation_id, "c");
+        assert_eq!(reply.id, "1");
+
+        let failed = anyhow::anyhow!("boom").context("failed to handle");
+        let reply = RPCReply::new("c", &responder, &Err(failed));
+        assert!(!reply.is_success());
+        assert_eq!(reply.error, Some("failed to handle: boom".to_string()));
+    }
 
     #[test]
     fn rpc_message_can_be_displayed() {
         let data = RPCMessageData::UpdateHubSettings(vec![]);
-        let rpc_message = RPCMessage { data };
+        let rpc_message = RPCMessage::new(data);
         assert_eq!(rpc_message.to_string(), "update_hub_settings")
     }
 
@@ -126,4 +446,145 @@ mod tests {
         let data = RPCMessageData::UpdatePublisherSettings(vec![]);
         assert_eq!(data.to_string(), "update_publisher_settings")
     }
+
+    #[test]
+    fn legacy_envelope_defaults_to_version_1() {
+        let rpc_message = RPCMessage::der_from_string("{\"Data\":{\"PurgeChannels\":[]}}").unwrap();
+        assert_eq!(rpc_message.version, 1);
+        assert!(rpc_message.sender.is_none());
+        assert!(rpc_message.timestamp.is_none());
+    }
+
+    #[test]
+    fn envelope_carries_sender_and_version() {
+        let rpc_message = RPCMessage::new(RPCMessageData::PurgeChannels(vec![])).with_sender(
+            "id_1",
+            "name_1",
+            "publisher",
+        );
+        let serialized = rpc_message.ser_to_string().unwrap();
+        let deserialized = RPCMessage::der_from_string(serialized.as_str()).unwrap();
+        assert_eq!(deserialized.version, RPC_ENVELOPE_VERSION);
+        assert_eq!(deserialized.sender, rpc_message.sender);
+        assert!(deserialized.timestamp.is_some());
+    }
+
+    #[test]
+    fn unknown_kinds_are_kept() {
+        let serialized = "{\"Version\":3,\"Data\":{\"RebalanceHubs\":{\"Count\":2}}}";
+        let rpc_message = RPCMessage::der_from_string(serialized).unwrap();
+        assert!(rpc_message.is_unknown());
+        assert_eq!(rpc_message.to_string(), "unknown");
+
+        let RPCMessageData::Unknown(unknown) = &rpc_message.data else {
+            panic!("expected an unknown message");
+        };
+        assert_eq!(unknown.kind, "RebalanceHubs");
+        assert_eq!(unknown.raw, serde_json::json!({ "Count": 2 }));
+
+        // forwarded messages keep their original shape
+        let serialized = rpc_message.ser_to_string().unwrap();
+        assert!(serialized.contains("\"Data\":{\"RebalanceHubs\":{\"Count\":2}}"));
+    }
+
+    #[test]
+    fn unknown_unit_kinds_are_kept() {
+        let rpc_message = RPCMessage::der_from_string("{\"Data\":\"ShutdownEverything\"}").unwrap();
+        let RPCMessageData::Unknown(unknown) = &rpc_message.data else {
+            panic!("expected an unknown message");
+        };
+        assert_eq!(unknown.kind, "ShutdownEverything");
+        assert!(unknown.raw.is_null());
+    }
+
+    #[test]
+    fn malformed_known_kinds_are_errors() {
+        assert!(RPCMessage::der_from_string("{\"Data\":{\"PurgeChannels\":42}}").is_err());
+    }
+
+    #[test]
+    fn heartbeat_round_trips() {
+        let heartbeat = PublisherHeartbeatMessage {
+            id: "id_1".to_string(),
+            name: "name_1".to_string(),
+            namespace: "rhiaqey".to_string(),
+            channels: 3,
+            uptime: 60,
+            timestamp: 1,
+        };
+        let rpc_message = RPCMessage::new(RPCMessageData::PublisherHeartbeat(heartbeat.clone()));
+        assert_eq!(rpc_message.to_string(), "publisher_heartbeat");
+
+        let serialized = rpc_message.ser_to_string().unwrap();
+        let deserialized = RPCMessage::der_from_string(serialized.as_str()).unwrap();
+        let RPCMessageData::PublisherHeartbeat(deserialized) = deserialized.data else {
+            panic!("expected a heartbeat");
+        };
+        assert_eq!(deserialized, heartbeat);
+    }
+
+    #[test]
+    fn messages_route_to_their_destination() {
+        let hubs = [
+            RPCMessageData::UpdateHubSettings(vec![]),
+            RPCMessageData::CreateChannels(vec![]),
+            RPCMessageData::DeleteChannels(vec![]),
+            RPCMessageData::PurgeChannels(vec![]),
+        ];
+        for data in hubs {
+            assert_eq!(data.default_target(), Some(RPCTarget::Hubs), "{data}");
+        }
+
+        assert_eq!(
+            RPCMessageData::ClientConnected(ClientConnectedMessage {
+                client_id: "client_1".to_string(),
+                user_id: None,
+                channels: vec![],
+            })
+            .default_target(),
+            Some(RPCTarget::Events)
+        );
+        assert_eq!(
+            RPCMessageData::ClientDisconnected(ClientDisconnectedMessage {
+                client_id: "client_1".to_string(),
+                user_id: None,
+                channels: vec![],
+            })
+            .default_target(),
+            Some(RPCTarget::Events)
+        );
+        assert_eq!(
+            RPCMessageData::AssignChannels(vec![]).default_target(),
+            None
+        );
+        assert_eq!(
+            RPCMessageData::UpdatePublisherSettings(vec![]).default_target(),
+            None
+        );
+        assert_eq!(
+            RPCMessageData::Unknown(UnknownRPCMessageData {
+                kind: "Future".to_string(),
+                raw: serde_json::Value::Null,
+            })
+            .default_target(),
+            None
+        );
+    }
+
+    #[test]
+    fn targets_map_to_topics() {
+        assert_eq!(RPCTarget::Hubs.topic("ns"), "ns:hub:streams:pubsub:clean");
+        assert_eq!(
+            RPCTarget::Events.topic("ns"),
+            "ns:hub:streams:pubsub:events"
+        );
+        assert_eq!(
+            RPCTarget::Publisher("pub1".to_string()).topic("ns"),
+            "ns:publishers:pub1:streams:pubsub"
+        );
+        assert_eq!(
+            RPCTarget::Publishers.topic("ns"),
+            "ns:publishers:streams:pubsub"
+        );
+    }
 }
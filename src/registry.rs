@@ -0,0 +1,113 @@
+use crate::pubsub::PublisherHeartbeatMessage;
+use crate::topics;
+use anyhow::Context;
+use log::{trace, warn};
+use rustis::client::Client;
+use rustis::commands::{GenericCommands, SetCommands, StringCommands};
+use std::time::Duration;
+
+/// Records a heartbeat for a publisher instance. The entry expires after `ttl`
+/// unless it is refreshed by another heartbeat.
+pub async fn record_heartbeat_async(
+    client: &Client,
+    heartbeat: &PublisherHeartbeatMessage,
+    ttl: Duration,
+) -> anyhow::Result<()> {
+    let key = topics::publisher_instance_key(
+        heartbeat.namespace.as_str(),
+        heartbeat.name.as_str(),
+        heartbeat.id.as_str(),
+    );
+
+    let raw = serde_json::to_string(heartbeat).context("failed to serialize heartbeat")?;
+
+    client
+        .setex(key.clone(), ttl.as_secs().max(1), raw)
+        .await
+        .context("failed to store heartbeat")?;
+
+    let _: usize = client
+        .sadd(
+            topics::publisher_instances_key(heartbeat.namespace.as_str()),
+            key,
+        )
+        .await
+        .context("failed to index publisher instance")?;
+
+    trace!("recorded heartbeat for publisher {}", heartbeat.id);
+
+    Ok(())
+}
+
+/// Removes a publisher instance from the registry right away
+pub async fn remove_publisher_async(
+    client: &Client,
+    namespace: &str,
+    name: &str,
+    id: &str,
+) -> anyhow::Result<()> {
+    let key = topics::publisher_instance_key(namespace, name, id);
+
+    let _: usize = client
+        .del(key.clone())
+        .await
+        .context("failed to remove publisher instance")?;
+
+    let _: usize = client
+        .srem(topics::publisher_instances_key(namespace), key)
+        .await
+        .context("failed to unindex publisher instance")?;
+
+    trace!("removed publisher {id} from registry");
+
+    Ok(())
+}
+
+/// Lists the latest heartbeat of every live publisher instance in `namespace`.
+/// Instances whose heartbeat has expired are dropped from the index.
+pub async fn list_live_publishers_async(
+    client: &Client,
+    namespace: &str,
+) -> anyhow::Result<Vec<PublisherHeartbeatMessage>> {
+    let index_key = topics::publisher_instances_key(namespace);
+
+    let keys: Vec<String> = client
+        .smembers(index_key.clone())
+        .await
+        .context("failed to read publisher instances")?;
+
+    if keys.is_empty() {
+        return Ok(vec![]);
+    }
+
+    let values: Vec<Option<String>> = client
+        .mget(keys.clone())
+        .await
+        .context("failed to read publisher heartbeats")?;
+
+    let mut live = vec![];
+    let mut expired = vec![];
+
+    for (key, value) in keys.into_iter().zip(values) {
+        let Some(value) = value else {
+            expired.push(key);
+            continue;
+        };
+
+        match serde_json::from_str::<PublisherHeartbeatMessage>(value.as_str()) {
+            Ok(heartbeat) => live.push(heartbeat),
+            Err(err) => warn!("failed to parse heartbeat at {key}: {err}"),
+        }
+    }
+
+    if !expired.is_empty() {
+        trace!("dropping {} expired publisher instance(s)", expired.len());
+
+        let _: usize = client
+            .srem(index_key, expired)
+            .await
+            .context("failed to drop expired publisher instances")?;
+    }
+
+    Ok(live)
+}
@@ -0,0 +1,356 @@
+use crate::pubsub::{
+    ClientConnectedMessage, ClientDisconnectedMessage, PublisherDeregistrationMessage,
+    PublisherHeartbeatMessage, PublisherRegistrationMessage, RPCMessage, RPCMessageData, RPCReply,
+    RPCSender, UnknownRPCMessageData,
+};
+use crate::stream::StreamMessage;
+use anyhow::Context;
+use futures_util::{FutureExt, Stream, StreamExt};
+use log::{debug, error, trace, warn};
+use rhiaqey_sdk_rs::channel::Channel;
+use rustis::client::{Client, PubSubMessage};
+use rustis::commands::PubSubCommands;
+use std::fmt::Display;
+use std::future::Future;
+use std::panic::AssertUnwindSafe;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// One method per `RPCMessageData` variant. Every method defaults to a no-op,
+/// so implementors only override the messages they care about.
+pub trait RPCHandler: Send + Sync {
+    fn register_publisher(
+        &self,
+        _message: PublisherRegistrationMessage,
+    ) -> impl Future<Output = anyhow::Result<()>> + Send {
+        async { Ok(()) }
+    }
+
+    fn deregister_publisher(
+        &self,
+        _message: PublisherDeregistrationMessage,
+    ) -> impl Future<Output = anyhow::Result<()>> + Send {
+        async { Ok(()) }
+    }
+
+    fn publisher_heartbeat(
+        &self,
+        _message: PublisherHeartbeatMessage,
+    ) -> impl Future<Output = anyhow::Result<()>> + Send {
+        async { Ok(()) }
+    }
+
+    fn notify_clients(
+        &self,
+        _message: StreamMessage,
+    ) -> impl Future<Output = anyhow::Result<()>> + Send {
+        async { Ok(()) }
+    }
+
+    fn update_hub_settings(
+        &self,
+        _settings: Vec<u8>,
+    ) -> impl Future<Output = anyhow::Result<()>> + Send {
+        async { Ok(()) }
+    }
+
+    fn update_publisher_settings(
+        &self,
+        _settings: Vec<u8>,
+    ) -> impl Future<Output = anyhow::Result<()>> + Send {
+        async { Ok(()) }
+    }
+
+    fn create_channels(
+        &self,
+        _channels: Vec<Channel>,
+    ) -> impl Future<Output = anyhow::Result<()>> + Send {
+        async { Ok(()) }
+    }
+
+    fn delete_channels(
+        &self,
+        _channels: Vec<Channel>,
+    ) -> impl Future<Output = anyhow::Result<()>> + Send {
+        async { Ok(()) }
+    }
+
+    fn purge_channels(
+        &self,
+        _channels: Vec<String>,
+    ) -> impl Future<Output = anyhow::Result<()>> + Send {
+        async { Ok(()) }
+    }
+
+    fn assign_channels(
+        &self,
+        _channels: Vec<Channel>,
+    ) -> impl Future<Output = anyhow::Result<()>> + Send {
+        async { Ok(()) }
+    }
+
+    fn client_connected(
+        &self,
+        _message: ClientConnectedMessage,
+    ) -> impl Future<Output = anyhow::Result<()>> + Send {
+        async { Ok(()) }
+    }
+
+    fn client_disconnected(
+        &self,
+        _message: ClientDisconnectedMessage,
+    ) -> impl Future<Output = anyhow::Result<()>> + Send {
+        async { Ok(()) }
+    }
+
+    /// Called for message kinds sent by newer peers
+    fn unknown(
+        &self,
+        _message: UnknownRPCMessageData,
+    ) -> impl Future<Output = anyhow::Result<()>> + Send {
+        async { Ok(()) }
+    }
+}
+
+/// Publishes `reply` to the `reply_to` topic of the request it answers
+pub async fn reply_async(client: &Client, reply_to: &str, reply: &RPCReply) -> anyhow::Result<()> {
+    let raw = reply
+        .ser_to_string()
+        .context("failed to serialize to string")?;
+
+    let _: usize = client
+        .publish(reply_to.to_string(), raw)
+        .await
+        .context("failed to publish rpc reply")?;
+
+    trace!("replied to rpc request {}", reply.correlation_id);
+
+    Ok(())
+}
+
+#[derive(Default, Debug)]
+pub struct RPCDispatchStats {
+    pub received: AtomicU64,
+    pub dispatched: AtomicU64,
+    pub parse_failures: AtomicU64,
+    pub handler_failures: AtomicU64,
+    pub handler_panics: AtomicU64,
+    pub reply_failures: AtomicU64,
+}
+
+/// Routes rpc messages to an `RPCHandler`. A handler that fails or panics only
+/// affects the message it was handling. Note that panics can only be caught
+/// when the binary is not built with `panic = "abort"`.
+pub struct RPCDispatcher<H: RPCHandler> {
+    handler: H,
+    stats: Arc<RPCDispatchStats>,
+    responder: Option<(Client, RPCSender)>,
+}
+
+impl<H: RPCHandler> RPCDispatcher<H> {
+    pub fn new(handler: H) -> Self {
+        RPCDispatcher {
+            handler,
+            stats: Arc::new(RPCDispatchStats::default()),
+            responder: None,
+        }
+    }
+
+    /// Replies as `responder` over `client` to every message that expects a reply,
+    /// reporting whether its handler succeeded
+    pub fn with_replies(mut self, client: Client, responder: RPCSender) -> Self {
+        self.responder = Some((client, responder));
+        self
+    }
+
+    pub fn handler(&self) -> &H {
+        &self.handler
+    }
+
+    /// Counters that can be read while the dispatcher is running
+    pub fn stats(&self) -> Arc<RPCDispatchStats> {
+        self.stats.clone()
+    }
+
+    pub async fn dispatch(&self, message: RPCMessage) {
+        let kind = message.data.to_string();
+        trace!("dispatching {kind} rpc message");
+
+        let reply_to = match (message.correlation_id, message.reply_to) {
+            (Some(correlation_id), Some(reply_to)) => Some((correlation_id, reply_to)),
+            _ => None,
+        };
+
+        let result = AssertUnwindSafe(self.route(message.data))
+            .catch_unwind()
+            .await;
+
+        let result = match result {
+            Ok(Ok(())) => {
+                self.stats.dispatched.fetch_add(1, Ordering::Relaxed);
+                Ok(())
+            }
+            Ok(Err(err)) => {
+                self.stats.handler_failures.fetch_add(1, Ordering::Relaxed);
+                warn!("{kind} handler failed: {err:#}");
+                Err(err)
+            }
+            Err(_) => {
+                self.stats.handler_panics.fetch_add(1, Ordering::Relaxed);
+                error!("{kind} handler panicked");
+                Err(anyhow::anyhow!("{kind} handler panicked"))
+            }
+        };
+
+        let (Some((client, responder)), Some((correlation_id, reply_to))) =
+            (&self.responder, reply_to)
+        else {
+            return;
+        };
+
+        let reply = RPCReply::new(correlation_id.as_str(), responder, &result);
+        if let Err(err) = reply_async(client, reply_to.as_str(), &reply).await {
+            self.stats.reply_failures.fetch_add(1, Ordering::Relaxed);
+            warn!("failed to reply to {kind} rpc message: {err:#}");
+        }
+    }
+
+    pub async fn dispatch_payload(&self, payload: &[u8]) {
+        self.stats.received.fetch_add(1, Ordering::Relaxed);
+
+        match serde_json::from_slice::<RPCMessage>(payload) {
+            Ok(message) => self.dispatch(message).await,
+            Err(err) => {
+                self.stats.parse_failures.fetch_add(1, Ordering::Relaxed);
+                warn!("failed to parse rpc message: {err}");
+            }
+        }
+    }
+
+    /// Dispatches every message of a pubsub subscription until it ends
+    pub async fn run<S, E>(&self, stream: S)
+    where
+        S: Stream<Item = Result<PubSubMessage, E>>,
+        E: Display,
+    {
+        let mut stream = std::pin::pin!(stream);
+
+        while let Some(message) = stream.next().await {
+            match message {
+                Ok(message) => self.dispatch_payload(message.payload.as_slice()).await,
+                Err(err) => warn!("failed to receive rpc message: {err}"),
+            }
+        }
+
+        debug!("rpc dispatcher stopped");
+    }
+
+    async fn route(&self, data: RPCMessageData) -> anyhow::Result<()> {
+        match data {
+            RPCMessageData::RegisterPublisher(message) => {
+                self.handler.register_publisher(message).await
+            }
+            RPCMessageData::DeregisterPublisher(message) => {
+                self.handler.deregister_publisher(message).await
+            }
+            RPCMessageData::PublisherHeartbeat(message) => {
+                self.handler.publisher_heartbeat(message).await
+            }
+            RPCMessageData::NotifyClients(message) => self.handler.notify_clients(message).await,
+            RPCMessageData::UpdateHubSettings(settings) => {
+                self.handler.update_hub_settings(settings).await
+            }
+            RPCMessageData::UpdatePublisherSettings(settings) => {
+                self.handler.update_publisher_settings(settings).await
+            }
+            RPCMessageData::CreateChannels(channels) => {
+                self.handler.create_channels(channels).await
+            }
+            RPCMessageData::DeleteChannels(channels) => {
+                self.handler.delete_channels(channels).await
+            }
+            RPCMessageData::PurgeChannels(channels) => self.handler.purge_channels(channels).await,
+            RPCMessageData::AssignChannels(channels) => {
+                self.handler.assign_channels(channels).await
+            }
+            RPCMessageData::ClientConnected(message) => {
+                self.handler.client_connected(message).await
+            }
+            RPCMessageData::ClientDisconnected(message) => {
+                self.handler.client_disconnected(message).await
+            }
+            RPCMessageData::Unknown(message) => {
+                debug!("received unknown rpc message kind {}", message.kind);
+                self.handler.unknown(message).await
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::pubsub::{RPCMessage, RPCMessageData};
+    use crate::rpc::{RPCDispatcher, RPCHandler};
+    use anyhow::bail;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[derive(Default)]
+    struct CountingHandler {
+        purged: AtomicUsize,
+    }
+
+    impl RPCHandler for CountingHandler {
+        async fn purge_channels(&self, channels: Vec<String>) -> anyhow::Result<()> {
+            self.purged.fetch_add(channels.len(), Ordering::Relaxed);
+            Ok(())
+        }
+
+        async fn update_hub_settings(&self, _settings: Vec<u8>) -> anyhow::Result<()> {
+            bail!("cannot update settings")
+        }
+
+        async fn update_publisher_settings(&self, _settings: Vec<u8>) -> anyhow::Result<()> {
+            panic!("handler bug")
+        }
+    }
+
+    fn payload(data: RPCMessageData) -> Vec<u8> {
+        RPCMessage::new(data).ser_to_string().unwrap().into_bytes()
+    }
+
+    #[tokio::test]
+    async fn routes_to_the_right_handler() {
+        let dispatcher = RPCDispatcher::new(CountingHandler::default());
+        let data = RPCMessageData::PurgeChannels(vec!["a".to_string(), "b".to_string()]);
+        dispatcher.dispatch_payload(payload(data).as_slice()).await;
+        // default no-op handler
+        dispatcher
+            .dispatch_payload(payload(RPCMessageData::CreateChannels(vec![])).as_slice())
+            .await;
+
+        assert_eq!(dispatcher.handler().purged.load(Ordering::Relaxed), 2);
+        assert_eq!(dispatcher.stats().dispatched.load(Ordering::Relaxed), 2);
+    }
+
+    #[tokio::test]
+    async fn counts_failures_and_keeps_going() {
+        let dispatcher = RPCDispatcher::new(CountingHandler::default());
+        dispatcher.dispatch_payload(b"not json").await;
+        dispatcher
+            .dispatch_payload(payload(RPCMessageData::UpdateHubSettings(vec![])).as_slice())
+            .await;
+        dispatcher
+            .dispatch_payload(payload(RPCMessageData::UpdatePublisherSettings(vec![])).as_slice())
+            .await;
+        dispatcher
+            .dispatch_payload(payload(RPCMessageData::PurgeChannels(vec![])).as_slice())
+            .await;
+
+        let stats = dispatcher.stats();
+        assert_eq!(stats.received.load(Ordering::Relaxed), 4);
+        assert_eq!(stats.parse_failures.load(Ordering::Relaxed), 1);
+        assert_eq!(stats.handler_failures.load(Ordering::Relaxed), 1);
+        assert_eq!(stats.handler_panics.load(Ordering::Relaxed), 1);
+        assert_eq!(stats.dispatched.load(Ordering::Relaxed), 1);
+    }
+}
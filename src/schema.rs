@@ -0,0 +1,96 @@
+use crate::client::{
+    ClientCodec, ClientEncoding, ClientMessage, ClientMessageBatch, ClientMessageDataType,
+    ClientMessageValue, ClientMessageValueClientChannelSubscription,
+    ClientMessageValueClientConnection, ClientMessageValueDelta,
+};
+use crate::compression::Compression;
+use crate::pubsub::{
+    ClientConnectedMessage, ClientDisconnectedMessage, PublisherDeregistrationMessage,
+    PublisherHeartbeatMessage, PublisherRegistrationMessage, RPCMessage, RPCMessageData, RPCReply,
+    RPCSender,
+};
+use crate::security::SecurityKey;
+use crate::stream::{StreamMessage, StreamMessageDataType};
+use schemars::{JsonSchema, schema_for};
+use serde_json::{Map, Value};
+
+/// Must be bumped every time the shape of a wire type changes
+pub const WIRE_SCHEMA_VERSION: u32 = 5;
+
+fn insert<T: JsonSchema>(schemas: &mut Map<String, Value>) {
+    schemas.insert(T::schema_name().to_string(), schema_for!(T).to_value());
+}
+
+/// JSON schema of every public type that goes over redis or to clients, keyed by type name
+pub fn wire_schemas() -> Value {
+    let mut schemas = Map::new();
+
+    insert::<ClientMessageDataType>(&mut schemas);
+    insert::<ClientEncoding>(&mut schemas);
+    insert::<ClientCodec>(&mut schemas);
+    insert::<ClientMessageValueClientConnection>(&mut schemas);
+    insert::<ClientMessageValueClientChannelSubscription>(&mut schemas);
+    insert::<ClientMessageValueDelta>(&mut schemas);
+    insert::<ClientMessageValue>(&mut schemas);
+    insert::<ClientMessage>(&mut schemas);
+    insert::<ClientMessageBatch>(&mut schemas);
+    insert::<Compression>(&mut schemas);
+    insert::<StreamMessageDataType>(&mut schemas);
+    insert::<StreamMessage>(&mut schemas);
+    insert::<PublisherRegistrationMessage>(&mut schemas);
+    insert::<PublisherDeregistrationMessage>(&mut schemas);
+    insert::<PublisherHeartbeatMessage>(&mut schemas);
+    insert::<ClientConnectedMessage>(&mut schemas);
+    insert::<ClientDisconnectedMessage>(&mut schemas);
+    insert::<RPCMessageData>(&mut schemas);
+    insert::<RPCMessage>(&mut schemas);
+    insert::<RPCReply>(&mut schemas);
+    insert::<RPCSender>(&mut schemas);
+    insert::<SecurityKey>(&mut schemas);
+
+    serde_json::json!({
+        "version": WIRE_SCHEMA_VERSION,
+        "schemas": schemas,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::schema::{WIRE_SCHEMA_VERSION, wire_schemas};
+    use serde_json::Value;
+    use std::path::PathBuf;
+
+    fn snapshot_path(version: u32) -> PathBuf {
+        PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+            .join("schemas")
+            .join(format!("wire-v{version}.json"))
+    }
+
+    #[test]
+    fn every_wire_type_has_a_schema() {
+        let schemas = wire_schemas();
+        let schemas = schemas["schemas"].as_object().unwrap();
+        assert!(schemas.contains_key("ClientMessage"));
+        assert!(schemas.contains_key("StreamMessage"));
+        assert!(schemas.contains_key("RPCMessage"));
+        assert!(schemas.contains_key("SecurityKey"));
+    }
+
+    #[test]
+    fn wire_shape_matches_schema_version() {
+        let path = snapshot_path(WIRE_SCHEMA_VERSION);
+        let snapshot = std::fs::read_to_string(&path).unwrap_or_else(|_| {
+            panic!(
+                "missing schema snapshot {}, write wire_schemas() output there",
+                path.display()
+            )
+        });
+        let snapshot: Value = serde_json::from_str(snapshot.as_str()).unwrap();
+
+        assert_eq!(
+            wire_schemas(),
+            snapshot,
+            "wire types changed: bump WIRE_SCHEMA_VERSION and add a new schema snapshot"
+        );
+    }
+}
@@ -0,0 +1,269 @@
+use crate::executor::TrimThreshold;
+use std::time::Duration;
+
+/// `GUARDED_XADD` added the entry, the value is its id
+pub const GUARDED_XADD_ADDED: i64 = 1;
+
+/// `GUARDED_XADD` found the idempotency key, the value is the original entry's id
+pub const GUARDED_XADD_DUPLICATE: i64 = 0;
+
+/// `GUARDED_XADD` found a newer timestamp, the value is that timestamp
+pub const GUARDED_XADD_STALE: i64 = 2;
+
+/// Adds a stream entry unless its idempotency key was seen within the ttl, or a newer
+/// timestamp was already accepted for the same category and key. Once added, the entry
+/// can also replace the snapshot of its key. Only the keys of enabled checks and updates
+/// are passed, so every declared key is one the script touches.
+///
+/// KEYS[1] stream, followed by the idempotency key, the accepted timestamp key of the
+/// category and key and the snapshot key and index, each only when enabled.
+/// ARGV[1..3] `1` to enable the idempotency check, the stale write check and the
+/// snapshot update, ARGV[4] idempotency ttl in milliseconds, ARGV[5] message timestamp,
+/// ARGV[6] accepted timestamp ttl in milliseconds, ARGV[7] snapshot value, ARGV[8..]
+/// `XADD` arguments after the stream key.
+/// Returns `{status, value}`, see the `GUARDED_XADD_*` constants.
+pub const GUARDED_XADD: &str = r#"
+local key = 1
+local function next_key(enabled)
+    if enabled == '1' then
+        key = key + 1
+        return KEYS[key]
+    end
+    return nil
+end
+local idempotency = next_key(ARGV[1])
+local timestamp = next_key(ARGV[2])
+local snapshot = next_key(ARGV[3])
+local snapshot_index = next_key(ARGV[3])
+if idempotency then
+    local existing = redis.call('GET', idempotency)
+    if existing then
+        return {0, existing}
+    end
+end
+if timestamp then
+    local last = redis.call('GET', timestamp)
+    if last and tonumber(last) > tonumber(ARGV[5]) then
+        return {2, last}
+    end
+end
+local id = redis.call('XADD', KEYS[1], unpack(ARGV, 8))
+if idempotency then
+    redis.call('SET', idempotency, id, 'PX', ARGV[4])
+end
+if timestamp then
+    redis.call('SET', timestamp, ARGV[5], 'PX', ARGV[6])
+end
+if snapshot then
+    redis.call('SET', snapshot, ARGV[7])
+    redis.call('SADD', snapshot_index, snapshot)
+end
+return {1, id}
+"#;
+
+/// Removes the members of a sorted set scored within a range and returns them, so that
+/// concurrent prunes never report the same member twice.
+///
+/// KEYS[1] sorted set, ARGV[1] and ARGV[2] the `BYSCORE` range.
+pub const PRUNE_EXPIRED: &str = r#"
+local expired = redis.call('ZRANGE', KEYS[1], ARGV[1], ARGV[2], 'BYSCORE')
+for i = 1, #expired, 1000 do
+    redis.call('ZREM', KEYS[1], unpack(expired, i, math.min(i + 999, #expired)))
+end
+return expired
+"#;
+
+/// Keys and arguments of a `GUARDED_XADD` call. `None` turns the matching check or
+/// update off.
+#[derive(Clone, Debug, Default)]
+pub struct GuardedXAdd {
+    pub stream: String,
+    /// Idempotency key and how long it is remembered
+    pub idempotency: Option<(String, Duration)>,
+    /// Accepted timestamp key and message timestamp
+    pub timestamp: Option<(String, u64)>,
+    /// How long the accepted timestamp is kept after the last accepted message
+    pub timestamp_ttl: Duration,
+    /// Snapshot key, snapshot index and snapshot value
+    pub snapshot: Option<(String, String, String)>,
+    /// `XADD` arguments after the stream key, see `xadd_args`
+    pub xadd_args: Vec<Vec<u8>>,
+}
+
+impl GuardedXAdd {
+    pub fn keys(&self) -> Vec<String> {
+        let mut keys = vec![self.stream.clone()];
+
+        if let Some((key, _)) = &self.idempotency {
+            keys.push(key.clone());
+        }
+
+        if let Some((key, _)) = &self.timestamp {
+            keys.push(key.clone());
+        }
+
+        if let Some((key, index_key, _)) = &self.snapshot {
+            keys.push(key.clone());
+            keys.push(index_key.clone());
+        }
+
+        keys
+    }
+
+    pub fn args(&self) -> Vec<Vec<u8>> {
+        let flag = |enabled: bool| {
+            if enabled {
+                b"1".to_vec()
+            } else {
+                b"0".to_vec()
+            }
+        };
+
+        let ttl = self
+            .idempotency
+            .as_ref()
+            .map(|(_, ttl)| ttl.as_millis())
+            .unwrap_or_default();
+
+        let timestamp = self
+            .timestamp
+            .as_ref()
+            .map(|(_, timestamp)| *timestamp)
+            .unwrap_or_default();
+
+        let snapshot = self
+            .snapshot
+            .as_ref()
+            .map(|(_, _, value)| value.clone())
+            .unwrap_or_default();
+
+        let mut args = vec![
+            flag(self.idempotency.is_some()),
+            flag(self.timestamp.is_some()),
+            flag(self.snapshot.is_some()),
+            ttl.to_string().into_bytes(),
+            timestamp.to_string().into_bytes(),
+            self.timestamp_ttl.as_millis().to_string().into_bytes(),
+            snapshot.into_bytes(),
+        ];
+        args.extend(self.xadd_args.iter().cloned());
+
+        args
+    }
+}
+
+/// Scope of the accepted timestamp key for a category and key. Encoded as a json
+/// pair so that separators inside either part cannot collide.
+pub fn timestamp_scope(category: Option<&str>, key: &str) -> String {
+    serde_json::json!([category.unwrap_or_default(), key]).to_string()
+}
+
+/// `XADD` arguments following the stream key: trim options, an auto id and the fields
+pub fn xadd_args(
+    threshold: Option<TrimThreshold>,
+    exact: bool,
+    fields: &[(&str, Vec<u8>)],
+) -> Vec<Vec<u8>> {
+    let operator = if exact { "=" } else { "~" };
+
+    let mut args: Vec<Vec<u8>> = match threshold {
+        Some(TrimThreshold::MaxLen(max_len)) => vec![
+            b"MAXLEN".to_vec(),
+            operator.into(),
+            max_len.to_string().into_bytes(),
+        ],
+        Some(TrimThreshold::MinId(min_id)) => {
+            vec![b"MINID".to_vec(), operator.into(), min_id.into_bytes()]
+        }
+        None => vec![],
+    };
+
+    args.push(b"*".to_vec());
+
+    for (name, value) in fields {
+        args.push(name.as_bytes().to_vec());
+        args.push(value.clone());
+    }
+
+    args
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::executor::TrimThreshold;
+    use crate::scripts::{GuardedXAdd, timestamp_scope, xadd_args};
+    use std::time::Duration;
+
+    fn strings(args: Vec<Vec<u8>>) -> Vec<String> {
+        args.into_iter()
+            .map(|arg| String::from_utf8(arg).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn xadd_args_without_trim() {
+        let args = xadd_args(None, false, &[("raw", b"{}".to_vec())]);
+        assert_eq!(strings(args), vec!["*", "raw", "{}"]);
+    }
+
+    #[test]
+    fn xadd_args_with_trim() {
+        let args = xadd_args(
+            Some(TrimThreshold::MaxLen(10)),
+            false,
+            &[("raw", b"{}".to_vec()), ("tag", vec![])],
+        );
+        assert_eq!(
+            strings(args),
+            vec!["MAXLEN", "~", "10", "*", "raw", "{}", "tag", ""]
+        );
+
+        let args = xadd_args(Some(TrimThreshold::MinId("5-0".to_string())), true, &[]);
+        assert_eq!(strings(args), vec!["MINID", "=", "5-0", "*"]);
+    }
+
+    #[test]
+    fn timestamp_scopes_do_not_collide() {
+        assert_eq!(timestamp_scope(Some("a"), "b"), r#"["a","b"]"#);
+        assert_eq!(timestamp_scope(None, "b"), r#"["","b"]"#);
+        assert_ne!(
+            timestamp_scope(Some("a:b"), "c"),
+            timestamp_scope(Some("a"), "b:c")
+        );
+    }
+
+    #[test]
+    fn guarded_xadd_only_declares_enabled_keys() {
+        let call = GuardedXAdd {
+            stream: "stream".to_string(),
+            xadd_args: xadd_args(None, false, &[]),
+            ..Default::default()
+        };
+        assert_eq!(call.keys(), vec!["stream"]);
+        assert_eq!(
+            strings(call.args()),
+            vec!["0", "0", "0", "0", "0", "0", "", "*"]
+        );
+
+        let call = GuardedXAdd {
+            stream: "stream".to_string(),
+            timestamp: Some(("timestamp".to_string(), 42)),
+            timestamp_ttl: Duration::from_secs(60),
+            snapshot: Some((
+                "snapshot".to_string(),
+                "index".to_string(),
+                "{}".to_string(),
+            )),
+            xadd_args: xadd_args(None, false, &[]),
+            ..Default::default()
+        };
+        assert_eq!(
+            call.keys(),
+            vec!["stream", "timestamp", "snapshot", "index"]
+        );
+        assert_eq!(
+            strings(call.args()),
+            vec!["0", "1", "1", "0", "42", "60000", "{}", "*"]
+        );
+    }
+}
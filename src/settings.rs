@@ -0,0 +1,220 @@
+use serde_json::Value;
+use std::fmt::{Display, Formatter};
+use std::sync::Arc;
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SettingsFieldError {
+    /// JSON pointer to the offending field, empty for the document root
+    pub path: String,
+
+    /// Human-readable reason the field was rejected
+    pub message: String,
+}
+
+impl Display for SettingsFieldError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        if self.path.is_empty() {
+            write!(f, "/: {}", self.message)
+        } else {
+            write!(f, "{}: {}", self.path, self.message)
+        }
+    }
+}
+
+/// Returned, wrapped in `anyhow::Error`, when settings do not match the
+/// publisher's schema. Use `downcast_ref` to get to the individual fields.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SettingsValidationError {
+    pub errors: Vec<SettingsFieldError>,
+}
+
+impl Display for SettingsValidationError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "settings failed schema validation: ")?;
+        for (i, error) in self.errors.iter().enumerate() {
+            if i > 0 {
+                write!(f, "; ")?;
+            }
+            write!(f, "{}", error)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for SettingsValidationError {}
+
+/// Validates a settings document against a JSON schema and collects every
+/// failing field instead of stopping at the first one
+pub fn validate_settings(schema: &Value, settings: &Value) -> anyhow::Result<()> {
+    let validator = jsonschema::validator_for(schema)
+        .map_err(|err| anyhow::anyhow!("invalid settings schema: {err}"))?;
+
+    let errors = validator
+        .iter_errors(settings)
+        .map(|err| SettingsFieldError {
+            path: err.instance_path.to_string(),
+            message: err.to_string(),
+        })
+        .collect::<Vec<_>>();
+
+    if errors.is_empty() {
+        return Ok(());
+    }
+
+    Err(SettingsValidationError { errors }.into())
+}
+
+/// Latest known settings. `settings` always holds the last document that decoded
+/// and validated successfully, while `error` holds the reason the most recent
+/// update was rejected, if it was.
+#[derive(Debug)]
+pub struct SettingsState<S> {
+    pub settings: Arc<S>,
+
+    /// Incremented on every accepted update
+    pub revision: u64,
+
+    pub error: Option<String>,
+}
+
+impl<S> Clone for SettingsState<S> {
+    fn clone(&self) -> Self {
+        SettingsState {
+            settings: self.settings.clone(),
+            revision: self.revision,
+            error: self.error.clone(),
+        }
+    }
+}
+
+impl<S> SettingsState<S> {
+    pub fn new(settings: S) -> Self {
+        SettingsState {
+            settings: Arc::new(settings),
+            revision: 0,
+            error: None,
+        }
+    }
+
+    /// Replaces the settings and clears any previous error
+    pub fn accept(&mut self, settings: S) {
+        self.settings = Arc::new(settings);
+        self.revision += 1;
+        self.error = None;
+    }
+
+    /// Keeps the current settings and records why the update was rejected
+    pub fn reject(&mut self, error: String) {
+        self.error = Some(error);
+    }
+}
+
+/// Handle returned by `Executor::watch_settings_async`. The background task that
+/// reloads settings stops when the watcher is dropped.
+pub struct SettingsWatcher<S> {
+    receiver: watch::Receiver<SettingsState<S>>,
+    handle: JoinHandle<()>,
+    revision: u64,
+}
+
+impl<S> SettingsWatcher<S> {
+    pub fn new(receiver: watch::Receiver<SettingsState<S>>, handle: JoinHandle<()>) -> Self {
+        let revision = receiver.borrow().revision;
+        SettingsWatcher {
+            receiver,
+            handle,
+            revision,
+        }
+    }
+
+    /// Last accepted settings
+    pub fn current(&self) -> Arc<S> {
+        self.receiver.borrow().settings.clone()
+    }
+
+    pub fn state(&self) -> SettingsState<S> {
+        self.receiver.borrow().clone()
+    }
+
+    /// Receiver that is notified on every accepted or rejected update
+    pub fn subscribe(&self) -> watch::Receiver<SettingsState<S>> {
+        self.receiver.clone()
+    }
+
+    /// Waits for an accepted update newer than the one last returned, skipping
+    /// rejected ones
+    pub async fn changed(&mut self) -> anyhow::Result<Arc<S>> {
+        let revision = self.revision;
+        let state = self
+            .receiver
+            .wait_for(|state| state.revision > revision)
+            .await
+            .map_err(|_| anyhow::anyhow!("settings watcher has stopped"))?;
+        self.revision = state.revision;
+        Ok(state.settings.clone())
+    }
+}
+
+impl<S> Drop for SettingsWatcher<S> {
+    fn drop(&mut self) {
+        self.handle.abort();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::settings::{
+        SettingsState, SettingsValidationError, SettingsWatcher, validate_settings,
+    };
+    use serde_json::json;
+
+    fn schema() -> serde_json::Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "Url": { "type": "string" },
+                "Interval": { "type": "integer", "minimum": 1 }
+            },
+            "required": ["Url"]
+        })
+    }
+
+    #[test]
+    fn valid_settings_pass() {
+        let settings = json!({ "Url": "https://example.com", "Interval": 10 });
+        assert!(validate_settings(&schema(), &settings).is_ok());
+    }
+
+    #[test]
+    fn every_invalid_field_is_reported() {
+        let settings = json!({ "Interval": 0 });
+        let error = validate_settings(&schema(), &settings).unwrap_err();
+        let error = error.downcast_ref::<SettingsValidationError>().unwrap();
+        assert_eq!(error.errors.len(), 2);
+        assert!(error.errors.iter().any(|x| x.path == "/Interval"));
+        assert!(error.errors.iter().any(|x| x.path.is_empty()));
+    }
+
+    #[test]
+    fn invalid_schema_is_an_error() {
+        let schema = json!({ "type": "not-a-type" });
+        assert!(validate_settings(&schema, &json!({})).is_err());
+    }
+
+    #[tokio::test]
+    async fn rejected_updates_keep_previous_settings() {
+        let (sender, receiver) = tokio::sync::watch::channel(SettingsState::new(1u32));
+        let mut watcher = SettingsWatcher::new(receiver, tokio::spawn(async {}));
+
+        sender.send_modify(|state| state.reject("bad settings".to_string()));
+        assert_eq!(*watcher.current(), 1);
+        assert_eq!(watcher.state().error, Some("bad settings".to_string()));
+
+        sender.send_modify(|state| state.accept(2));
+        assert_eq!(*watcher.changed().await.unwrap(), 2);
+        assert_eq!(watcher.state().revision, 1);
+        assert_eq!(watcher.state().error, None);
+    }
+}
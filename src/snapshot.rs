@@ -0,0 +1,170 @@
+use crate::stream::StreamMessage;
+use crate::topics;
+use anyhow::Context;
+use log::{trace, warn};
+use rustis::client::Client;
+use rustis::commands::{GenericCommands, SetCommands, StringCommands};
+
+/// Category used for messages that do not name one
+pub const DEFAULT_SNAPSHOT_CATEGORY: &str = "default";
+
+pub fn snapshot_category(category: Option<&str>) -> &str {
+    category
+        .filter(|category| !category.is_empty())
+        .unwrap_or(DEFAULT_SNAPSHOT_CATEGORY)
+}
+
+/// Snapshot key and index key of a message, going by its channel, category and key
+pub fn snapshot_keys(namespace: &str, message: &StreamMessage) -> (String, String) {
+    let category = snapshot_category(message.category.as_deref());
+
+    (
+        topics::hub_channel_snapshot_topic(
+            namespace,
+            message.channel.as_str(),
+            category,
+            message.key.as_str(),
+        ),
+        topics::hub_channel_snapshot_index_key(namespace, message.channel.as_str(), category),
+    )
+}
+
+/// Stores `message` as the latest value of its channel, category and key
+pub async fn set_snapshot_async(
+    client: &Client,
+    namespace: &str,
+    message: &StreamMessage,
+) -> anyhow::Result<()> {
+    let (key, index_key) = snapshot_keys(namespace, message);
+
+    let raw = message
+        .ser_to_string()
+        .context("failed to serialize snapshot")?;
+
+    client
+        .set(key.clone(), raw)
+        .await
+        .context("failed to store snapshot")?;
+
+    let _: usize = client
+        .sadd(index_key, key.clone())
+        .await
+        .context("failed to index snapshot")?;
+
+    trace!("stored snapshot {key}");
+
+    Ok(())
+}
+
+pub async fn get_snapshot_async(
+    client: &Client,
+    namespace: &str,
+    channel: &str,
+    category: Option<&str>,
+    key: &str,
+) -> anyhow::Result<Option<StreamMessage>> {
+    let key =
+        topics::hub_channel_snapshot_topic(namespace, channel, snapshot_category(category), key);
+
+    let raw: Option<String> = client
+        .get(key)
+        .await
+        .context("failed to retrieve snapshot")?;
+
+    let Some(raw) = raw else {
+        return Ok(None);
+    };
+
+    let message =
+        StreamMessage::der_from_string(raw.as_str()).context("failed to deserialize snapshot")?;
+
+    Ok(Some(message))
+}
+
+/// Latest message of every key in a channel's category, for clients that join late
+pub async fn list_snapshots_async(
+    client: &Client,
+    namespace: &str,
+    channel: &str,
+    category: Option<&str>,
+) -> anyhow::Result<Vec<StreamMessage>> {
+    let index_key =
+        topics::hub_channel_snapshot_index_key(namespace, channel, snapshot_category(category));
+
+    let keys: Vec<String> = client
+        .smembers(index_key.clone())
+        .await
+        .context("failed to read snapshot index")?;
+
+    if keys.is_empty() {
+        return Ok(vec![]);
+    }
+
+    let values: Vec<Option<String>> = client
+        .mget(keys.clone())
+        .await
+        .context("failed to read snapshots")?;
+
+    let mut messages = vec![];
+    let mut missing = vec![];
+
+    for (key, value) in keys.into_iter().zip(values) {
+        let Some(value) = value else {
+            missing.push(key);
+            continue;
+        };
+
+        match StreamMessage::der_from_string(value.as_str()) {
+            Ok(message) => messages.push(message),
+            Err(err) => warn!("failed to parse snapshot at {key}: {err:#}"),
+        }
+    }
+
+    if !missing.is_empty() {
+        let _: usize = client
+            .srem(index_key, missing)
+            .await
+            .context("failed to drop missing snapshots from index")?;
+    }
+
+    Ok(messages)
+}
+
+/// Returns whether there was a snapshot to delete
+pub async fn delete_snapshot_async(
+    client: &Client,
+    namespace: &str,
+    channel: &str,
+    category: Option<&str>,
+    key: &str,
+) -> anyhow::Result<bool> {
+    let category = snapshot_category(category);
+    let key = topics::hub_channel_snapshot_topic(namespace, channel, category, key);
+
+    let deleted: usize = client
+        .del(key.clone())
+        .await
+        .context("failed to delete snapshot")?;
+
+    let _: usize = client
+        .srem(
+            topics::hub_channel_snapshot_index_key(namespace, channel, category),
+            key,
+        )
+        .await
+        .context("failed to unindex snapshot")?;
+
+    Ok(deleted > 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::snapshot::{DEFAULT_SNAPSHOT_CATEGORY, snapshot_category};
+
+    #[test]
+    fn missing_or_empty_category_is_default() {
+        assert_eq!(snapshot_category(None), DEFAULT_SNAPSHOT_CATEGORY);
+        assert_eq!(snapshot_category(Some("")), DEFAULT_SNAPSHOT_CATEGORY);
+        assert_eq!(snapshot_category(Some("trades")), "trades");
+    }
+}
@@ -1,15 +1,17 @@
+use crate::compression::{CompressionOptions, compress, decompress};
 use anyhow::Context;
 use rhiaqey_sdk_rs::gateway::GatewayMessage;
 use rhiaqey_sdk_rs::message::MessageValue;
 use rhiaqey_sdk_rs::producer::ProducerMessage;
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
 pub enum StreamMessageDataType {
     Data = 0, // sent data from hub to client
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, Eq, PartialEq)]
 pub struct StreamMessage {
     // type of data we are sending to user
     #[serde(rename = "typ")]
@@ -24,6 +26,7 @@ pub struct StreamMessage {
 
     // Any value
     #[serde(rename = "val")]
+    #[schemars(with = "serde_json::Value")]
     pub value: MessageValue,
 
     // If timestamp is provided there will a check in timestamps.
@@ -57,6 +60,11 @@ pub struct StreamMessage {
     // gateway or producer id, useful for debugging
     #[serde(rename = "pid", skip_serializing_if = "Option::is_none")]
     pub publisher_id: Option<String>,
+
+    // retried publishes with the same key are stored only once. Defaults so that
+    // compact frames written before it existed still decode
+    #[serde(rename = "idk", default, skip_serializing_if = "Option::is_none")]
+    pub idempotency_key: Option<String>,
 }
 
 impl StreamMessage {
@@ -67,6 +75,48 @@ impl StreamMessage {
     pub fn der_from_string(message: &str) -> anyhow::Result<StreamMessage> {
         serde_json::from_str::<StreamMessage>(message).context("failed to deserialize")
     }
+
+    /// Json frame, compressed when the options ask for it and it is big enough
+    pub fn ser_to_compressed(&self, options: CompressionOptions) -> anyhow::Result<Vec<u8>> {
+        compress(self.ser_to_string()?.as_bytes(), options)
+    }
+
+    /// Accepts both compressed and plain json frames
+    pub fn der_from_compressed(message: &[u8]) -> anyhow::Result<StreamMessage> {
+        let data = decompress(message).context("failed to decompress")?;
+        serde_json::from_slice::<StreamMessage>(data.as_slice()).context("failed to deserialize")
+    }
+
+    pub fn ser_to_msgpack(&self) -> anyhow::Result<Vec<u8>> {
+        rmp_serde::to_vec_named(self).context("failed to serialize to msgpack")
+    }
+
+    /// Positional msgpack form. Every field is written in declaration order so
+    /// that field names are left out of the frame entirely.
+    pub fn ser_to_msgpack_compact(&self) -> anyhow::Result<Vec<u8>> {
+        rmp_serde::to_vec(&(
+            self.data_type,
+            &self.channel,
+            &self.key,
+            &self.value,
+            self.timestamp,
+            &self.tag,
+            &self.category,
+            self.size,
+            &self.client_ids,
+            &self.user_ids,
+            &self.hub_id,
+            &self.publisher_id,
+            &self.idempotency_key,
+        ))
+        .context("failed to serialize to compact msgpack")
+    }
+
+    /// Accepts both the named and the compact msgpack forms
+    pub fn der_from_msgpack(message: &[u8]) -> anyhow::Result<StreamMessage> {
+        rmp_serde::from_slice::<StreamMessage>(message)
+            .context("failed to deserialize from msgpack")
+    }
 }
 
 impl From<ProducerMessage> for StreamMessage {
@@ -84,6 +134,7 @@ impl From<ProducerMessage> for StreamMessage {
             client_ids: value.client_ids,
             hub_id: None,
             publisher_id: None,
+            idempotency_key: None,
         }
     }
 }
@@ -103,6 +154,148 @@ impl From<GatewayMessage> for StreamMessage {
             client_ids: value.client_ids,
             hub_id: None,
             publisher_id: None,
+            idempotency_key: None,
         }
     }
 }
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use crate::compression::{Compression, CompressionOptions};
+    use crate::stream::{StreamMessage, StreamMessageDataType};
+    use proptest::prelude::*;
+    use rhiaqey_sdk_rs::message::MessageValue;
+
+    pub(crate) fn arb_message_value() -> impl Strategy<Value = MessageValue> {
+        prop_oneof![
+            ".*".prop_map(MessageValue::Text),
+            prop::collection::vec(any::<u8>(), 0..64).prop_map(MessageValue::Binary),
+            prop::collection::btree_map("f_[a-z]{1,8}", any::<i64>(), 1..8)
+                .prop_map(|fields| { MessageValue::Json(serde_json::json!(fields)) }),
+        ]
+    }
+
+    pub(crate) fn arb_stream_message() -> impl Strategy<Value = StreamMessage> {
+        (
+            "[a-z0-9_]{0,16}",
+            "[a-z0-9_]{0,16}",
+            arb_message_value(),
+            any::<Option<u64>>(),
+            any::<Option<String>>(),
+            any::<Option<String>>(),
+            any::<Option<usize>>(),
+            any::<Option<Vec<String>>>(),
+            any::<Option<String>>(),
+            any::<Option<String>>(),
+        )
+            .prop_map(
+                |(
+                    channel,
+                    key,
+                    value,
+                    timestamp,
+                    tag,
+                    category,
+                    size,
+                    user_ids,
+                    publisher_id,
+                    idempotency_key,
+                )| {
+                    StreamMessage {
+                        data_type: StreamMessageDataType::Data as u8,
+                        channel,
+                        key,
+                        value,
+                        timestamp,
+                        tag,
+                        category,
+                        size,
+                        client_ids: None,
+                        user_ids,
+                        hub_id: None,
+                        publisher_id,
+                        idempotency_key,
+                    }
+                },
+            )
+    }
+
+    pub(crate) fn stream_message(key: &str) -> StreamMessage {
+        StreamMessage {
+            data_type: StreamMessageDataType::Data as u8,
+            channel: "channel_1".to_string(),
+            key: key.to_string(),
+            value: MessageValue::Text(String::from("some text")),
+            timestamp: None,
+            tag: None,
+            category: None,
+            size: None,
+            client_ids: None,
+            user_ids: None,
+            hub_id: None,
+            publisher_id: None,
+            idempotency_key: None,
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn json_round_trip(message in arb_stream_message()) {
+            let serialized = message.ser_to_string().unwrap();
+            prop_assert_eq!(StreamMessage::der_from_string(serialized.as_str()).unwrap(), message);
+        }
+
+        #[test]
+        fn msgpack_round_trip(message in arb_stream_message()) {
+            let serialized = message.ser_to_msgpack().unwrap();
+            prop_assert_eq!(StreamMessage::der_from_msgpack(serialized.as_slice()).unwrap(), message);
+        }
+
+        #[test]
+        fn compact_msgpack_round_trip(message in arb_stream_message()) {
+            let serialized = message.ser_to_msgpack_compact().unwrap();
+            prop_assert_eq!(StreamMessage::der_from_msgpack(serialized.as_slice()).unwrap(), message);
+        }
+
+        #[test]
+        fn compressed_round_trip(message in arb_stream_message()) {
+            let options = CompressionOptions { compression: Compression::Zstd, threshold: 0 };
+            let serialized = message.ser_to_compressed(options).unwrap();
+            prop_assert_eq!(StreamMessage::der_from_compressed(serialized.as_slice()).unwrap(), message);
+        }
+    }
+
+    #[test]
+    fn compact_msgpack_is_smaller() {
+        let mut message = stream_message("key_1");
+        message.timestamp = Some(1);
+        message.tag = Some("tag".to_string());
+        message.category = Some("category".to_string());
+        let named = message.ser_to_msgpack().unwrap();
+        let compact = message.ser_to_msgpack_compact().unwrap();
+        assert!(compact.len() < named.len());
+    }
+
+    #[test]
+    fn compact_msgpack_without_idempotency_key_decodes() {
+        let frame = rmp_serde::to_vec(&(
+            StreamMessageDataType::Data as u8,
+            "channel_1",
+            "key_1",
+            MessageValue::Text(String::from("some text")),
+            Some(1u64),
+            None::<String>,
+            None::<String>,
+            None::<usize>,
+            None::<Vec<String>>,
+            None::<Vec<String>>,
+            None::<String>,
+            None::<String>,
+        ))
+        .unwrap();
+
+        let message = StreamMessage::der_from_msgpack(frame.as_slice()).unwrap();
+        assert_eq!(message.key, "key_1");
+        assert!(message.idempotency_key.is_none());
+    }
+}
@@ -22,6 +22,18 @@ pub fn hub_to_publisher_pubsub_topic<S: AsRef<str>>(namespace: S, publisher_name
     )
 }
 
+pub fn hub_to_publishers_pubsub_topic<S: AsRef<str>>(namespace: S) -> String {
+    format!("{}:publishers:streams:pubsub", namespace.as_ref())
+}
+
+pub fn rpc_reply_pubsub_topic<S: AsRef<str>>(namespace: S, correlation_id: S) -> String {
+    format!(
+        "{}:rpc:replies:{}",
+        namespace.as_ref(),
+        correlation_id.as_ref()
+    )
+}
+
 pub fn hub_channel_snapshot_topic<S: AsRef<str>>(
     namespace: S,
     channel: S,
@@ -37,6 +49,37 @@ pub fn hub_channel_snapshot_topic<S: AsRef<str>>(
     )
 }
 
+pub fn hub_channel_snapshot_index_key<S: AsRef<str>>(
+    namespace: S,
+    channel: S,
+    category: S,
+) -> String {
+    format!(
+        "{}:hub:channels:{}:snapshots:{}",
+        namespace.as_ref(),
+        channel.as_ref(),
+        category.as_ref(),
+    )
+}
+
+pub fn publisher_idempotency_key<S: AsRef<str>>(namespace: S, channel: S, key: S) -> String {
+    format!(
+        "{}:hub:channels:{}:idempotency:{}",
+        namespace.as_ref(),
+        channel.as_ref(),
+        key.as_ref()
+    )
+}
+
+pub fn publisher_timestamp_key<S: AsRef<str>>(namespace: S, channel: S, scope: S) -> String {
+    format!(
+        "{}:hub:channels:{}:timestamps:{}",
+        namespace.as_ref(),
+        channel.as_ref(),
+        scope.as_ref()
+    )
+}
+
 pub fn hub_channels_key<S: AsRef<str>>(namespace: S) -> String {
     format!("{}:hub:channels", namespace.as_ref())
 }
@@ -73,6 +116,35 @@ pub fn publisher_schema_key<S: AsRef<str>>(namespace: S, publisher_name: S) -> S
     )
 }
 
+pub fn publisher_instances_key<S: AsRef<str>>(namespace: S) -> String {
+    format!("{}:publishers:instances", namespace.as_ref())
+}
+
+pub fn publisher_instance_key<S: AsRef<str>>(namespace: S, publisher_name: S, id: S) -> String {
+    format!(
+        "{}:publishers:{}:instances:{}",
+        namespace.as_ref(),
+        publisher_name.as_ref(),
+        id.as_ref()
+    )
+}
+
+pub fn presence_channels_key<S: AsRef<str>>(namespace: S) -> String {
+    format!("{}:presence:channels", namespace.as_ref())
+}
+
+pub fn presence_channel_key<S: AsRef<str>>(namespace: S, channel: S) -> String {
+    format!(
+        "{}:presence:channels:{}",
+        namespace.as_ref(),
+        channel.as_ref()
+    )
+}
+
+pub fn presence_user_key<S: AsRef<str>>(namespace: S, user_id: S) -> String {
+    format!("{}:presence:users:{}", namespace.as_ref(), user_id.as_ref())
+}
+
 pub fn security_key<S: AsRef<str>>(namespace: S) -> String {
     format!("{}:security", namespace.as_ref())
 }